@@ -0,0 +1,878 @@
+use super::*;
+use yaml_rust::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust::scanner::{Marker, ScanError, TScalarStyle};
+use yaml_rust::{Yaml, YamlLoader};
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+/// A YAML stream that keeps its source text.
+///
+/// Edits are recorded against the byte spans of the parsed nodes and are only
+/// applied when the stream is formatted, so that comments, blank lines,
+/// quoting and anchors outside of the touched nodes are written back as they
+/// were read.
+pub struct Document {
+    source: String,
+    docs: Vec<Yaml>,
+    nodes: Vec<Node>,
+    roots: Vec<usize>,
+    replaced: Vec<(usize, String)>,
+    deleted: Vec<usize>,
+}
+
+/// A node of the source and its byte span
+struct Node {
+    start: usize,
+    end: usize,
+    kind: Kind,
+    flow: bool,
+    /// The collection holding the node and the index of its entry
+    parent: Option<(usize, usize)>,
+}
+
+enum Kind {
+    Scalar(TScalarStyle),
+    /// The anchored node the alias refers to
+    Alias(Option<usize>),
+    Sequence(Vec<Entry>),
+    Mapping(Vec<Entry>),
+}
+
+/// A sequence item or a mapping pair
+struct Entry {
+    /// Key of a mapping pair, `Yaml::BadValue` for keys that are not scalars
+    key: Option<Yaml>,
+    /// Start of the key or of the `-` indicator
+    start: usize,
+    /// End of the `:` or `-` indicator
+    indicator: usize,
+    node: usize,
+}
+
+type Edit = (usize, usize, String);
+
+impl Node {
+    fn entries(&self) -> &[Entry] {
+        match &self.kind {
+            Kind::Sequence(entries) | Kind::Mapping(entries) => entries,
+            _ => &[],
+        }
+    }
+}
+
+impl Document {
+    pub fn parse(source: &str) -> Result<Document, ScanError> {
+        let docs = YamlLoader::load_from_str(source)?;
+        let mut builder = Builder {
+            source,
+            offsets: source.char_indices().map(|(i, _)| i).chain(Some(source.len())).collect(),
+            nodes: Vec::new(),
+            roots: Vec::new(),
+            stack: Vec::new(),
+            anchors: HashMap::new(),
+        };
+        Parser::new(source.chars()).load(&mut builder, true)?;
+        Ok(Document {
+            source: source.to_owned(),
+            docs,
+            nodes: builder.nodes,
+            roots: builder.roots,
+            replaced: Vec::new(),
+            deleted: Vec::new(),
+        })
+    }
+
+    /// Documents of the stream as they were read, edits are not applied to them
+    pub fn docs(&self) -> &[Yaml] {
+        &self.docs
+    }
+
+    /// Replaces the node at `path` of the `document`-th document with `value`.
+    ///
+    /// Collections are written in flow style and strings keep the quotes of
+    /// the scalar they replace.
+    pub fn set(&mut self, document: usize, path: &[PathSegment], value: &Yaml) -> Result<(), EditError> {
+        let id = self.find(document, path)?;
+        // The anchor of the node itself is kept, the ones inside it are not
+        self.check_aliases(document, path, |target| target != id && self.within(target, id))?;
+        let style = match self.nodes[id].kind {
+            Kind::Scalar(style) => style,
+            _ => TScalarStyle::Any,
+        };
+        self.replaced.push((id, flow_text(value, style)));
+        Ok(())
+    }
+
+    /// Removes the node at `path` of the `document`-th document from its
+    /// parent, or the whole document when `path` is empty.
+    ///
+    /// Nodes holding an anchor that is still referred to by an alias outside
+    /// of them are not removed, the aliases have to be removed first.
+    pub fn delete(&mut self, document: usize, path: &[PathSegment]) -> Result<(), EditError> {
+        let id = self.find(document, path)?;
+        self.check_aliases(document, path, |target| self.within(target, id))?;
+        self.deleted.push(id);
+        Ok(())
+    }
+
+    /// Fails when an alias that is kept in the output refers to an anchored
+    /// node for which `removed` holds
+    fn check_aliases(&self, document: usize, path: &[PathSegment], removed: impl Fn(usize) -> bool) -> Result<(), EditError> {
+        let dropped = |alias: usize| {
+            removed(alias)
+                || self.deleted.iter().any(|&id| self.within(alias, id))
+                || self.replaced.iter().any(|&(id, _)| self.within(alias, id))
+        };
+        for (alias, node) in self.nodes.iter().enumerate() {
+            if let Kind::Alias(Some(target)) = node.kind {
+                if removed(target) && !dropped(alias) {
+                    return Err(EditError::AliasedAnchor {
+                        document,
+                        path: path_string(path),
+                        anchor: self.source[node.start + 1..node.end].to_owned(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `id` is `ancestor` or one of its descendants
+    fn within(&self, mut id: usize, ancestor: usize) -> bool {
+        while id != ancestor {
+            match self.nodes[id].parent {
+                Some((parent, _)) => id = parent,
+                None => return false,
+            }
+        }
+        true
+    }
+
+    /// Whether the node at `path` of the `document`-th document is written
+    /// in the source, rather than reached through an alias or inherited
+    /// through a `<<` merge key
+    pub fn contains(&self, document: usize, path: &[PathSegment]) -> bool {
+        self.find(document, path).is_ok()
+    }
+
+    /// Runs `pipeline` over the documents and tells for every match the
+    /// anchor its value comes from
+    pub fn evaluate(&self, evaluator: &Evaluator, pipeline: &Pipeline) -> Result<Vec<Match<'_>>, EvalError> {
+        let mut matches = evaluator.evaluate_pipeline(pipeline, &self.docs)?;
+        for m in matches.iter_mut() {
+            if let Cow::Borrowed(_) = m.value {
+                m.anchor = self.anchor(m.document, &m.path).map(str::to_owned);
+            }
+        }
+        Ok(matches)
+    }
+
+    /// Name of the anchor the node at `path` was copied from through an alias
+    /// or inherited from through a `<<` merge key. The outermost one is
+    /// returned when the path goes through several.
+    pub fn anchor(&self, document: usize, path: &[PathSegment]) -> Option<&str> {
+        let mut id = *self.roots.get(document)?;
+        let mut anchor = None;
+        for segment in path {
+            let (child, via) = self.child(id, segment)?;
+            anchor = anchor.or(via);
+            id = child;
+        }
+        anchor.or(self.dealias(id).1)
+    }
+
+    /// The child of `id` at `segment` and the anchor it is reached through
+    fn child(&self, id: usize, segment: &PathSegment) -> Option<(usize, Option<&str>)> {
+        let (id, alias) = self.dealias(id);
+        let (entries, key) = match (&self.nodes[id].kind, segment) {
+            (Kind::Sequence(entries), PathSegment::Index(i)) => return Some((entries.get(*i)?.node, alias)),
+            (Kind::Mapping(entries), PathSegment::Key(key)) => (entries, key),
+            _ => return None,
+        };
+        if let Some(entry) = entries.iter().rev().find(|entry| entry.key.as_ref() == Some(key)) {
+            return Some((entry.node, alias));
+        }
+        let merge = Yaml::String("<<".to_owned());
+        let merge = entries.iter().find(|entry| entry.key.as_ref() == Some(&merge))?;
+        let (source, merged) = self.dealias(merge.node);
+        let sources = match &self.nodes[source].kind {
+            Kind::Sequence(entries) => entries.iter().map(|entry| entry.node).collect(),
+            _ => vec![source],
+        };
+        sources.into_iter()
+            .find_map(|source| self.child(source, segment))
+            .map(|(child, via)| (child, alias.or(merged).or(via)))
+    }
+
+    /// The node an alias refers to and the name of its anchor, other nodes are returned as is
+    fn dealias(&self, id: usize) -> (usize, Option<&str>) {
+        let node = &self.nodes[id];
+        match node.kind {
+            Kind::Alias(Some(target)) => (target, Some(&self.source[node.start + 1..node.end])),
+            _ => (id, None),
+        }
+    }
+
+    fn find(&self, document: usize, path: &[PathSegment]) -> Result<usize, EditError> {
+        let not_in_source = || EditError::NotInSource { document, path: path_string(path) };
+        let mut id = *self.roots.get(document).ok_or_else(not_in_source)?;
+        for segment in path {
+            let entry = match (&self.nodes[id].kind, segment) {
+                (Kind::Sequence(entries), PathSegment::Index(i)) => entries.get(*i),
+                // Later duplicate keys override earlier ones when loading
+                (Kind::Mapping(entries), PathSegment::Key(key)) => {
+                    entries.iter().rev().find(|entry| entry.key.as_ref() == Some(key))
+                },
+                _ => None,
+            };
+            id = entry.ok_or_else(not_in_source)?.node;
+        }
+        Ok(id)
+    }
+
+    /// Byte ranges to rewrite, sorted and without overlaps
+    fn edits(&self) -> Vec<Edit> {
+        let mut edits: Vec<Edit> = self.deleted.iter().map(|&id| self.deletion(id)).collect();
+        for (id, text) in self.replaced.iter() {
+            let (start, end, prefix) = self.value_span(*id);
+            edits.push((start, end, format!("{}{}", prefix, text)));
+        }
+        edits.sort_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)));
+
+        // Overlapping deletions are joined, edits inside a rewritten range are dropped
+        let mut merged: Vec<Edit> = Vec::new();
+        for edit in edits {
+            match merged.last_mut() {
+                Some(last) if edit.0 < last.1 || (edit.0, edit.1) == (last.0, last.1) => {
+                    if last.2.is_empty() && edit.2.is_empty() && edit.1 > last.1 {
+                        last.1 = edit.1;
+                    }
+                },
+                _ => merged.push(edit),
+            }
+        }
+        merged
+    }
+
+    /// The range holding the value of `id`. Empty values and block
+    /// sequences written at the indentation of their key are rewritten from
+    /// the end of the indicator so that the new value stays on its line.
+    fn value_span(&self, id: usize) -> (usize, usize, &'static str) {
+        let node = &self.nodes[id];
+        if let Some((parent, index)) = node.parent {
+            let entry = &self.nodes[parent].entries()[index];
+            let collection = matches!(node.kind, Kind::Sequence(_) | Kind::Mapping(_));
+            let compact = collection && !node.flow && self.column(node.start) <= self.column(entry.start);
+            if node.start == node.end || compact {
+                return (entry.indicator, node.end, " ");
+            }
+        }
+        (node.start, node.end, "")
+    }
+
+    fn deletion(&self, id: usize) -> Edit {
+        let (parent, index) = match self.nodes[id].parent {
+            Some(parent) => parent,
+            None => return self.document_range(id),
+        };
+        let node = &self.nodes[parent];
+        let entries = node.entries();
+        let end = |entry: &Entry| self.nodes[entry.node].end;
+
+        if !node.flow {
+            if entries.iter().all(|entry| self.deleted.contains(&entry.node)) {
+                // Block collections cannot be empty
+                let (start, end, prefix) = self.value_span(parent);
+                let text = match node.kind {
+                    Kind::Mapping(_) => "{}",
+                    _ => "[]",
+                };
+                return (start, end, format!("{}{}", prefix, text));
+            }
+            let (start, end) = self.line_range(entries[index].start, end(&entries[index]));
+            return (start, end, String::new());
+        }
+
+        // Flow entries take the following separator with them, the last
+        // one takes the separator after the last entry that is kept
+        if index + 1 < entries.len() {
+            (entries[index].start, entries[index + 1].start, String::new())
+        } else {
+            let kept = entries[..index].iter().rev().find(|entry| !self.deleted.contains(&entry.node));
+            match kept {
+                Some(kept) => (end(kept), end(&entries[index]), String::new()),
+                None => (entries[0].start, end(&entries[index]), String::new()),
+            }
+        }
+    }
+
+    /// Widens the span of a block entry to its whole lines, including a
+    /// trailing comment. An entry that shares its line with a `-` indicator
+    /// keeps the indicator and pulls the next entry up in its place.
+    fn line_range(&self, start: usize, end: usize) -> (usize, usize) {
+        let source = self.source.as_str();
+        let line_end = source[end..].find('\n').map_or(source.len(), |i| end + i + 1);
+        let tail = source[end..line_end].trim();
+        if !tail.is_empty() && !tail.starts_with('#') {
+            return (start, end);
+        }
+        let line_start = start - self.column(start);
+        if source[line_start..start].trim().is_empty() {
+            return (line_start, line_end);
+        }
+        let next = &source[line_end..];
+        let indent = next.len() - next.trim_start_matches(' ').len();
+        if indent == self.column(start) {
+            (start, line_end + indent)
+        } else {
+            (start, line_end)
+        }
+    }
+
+    /// From the `---` marker of the document up to the marker of the next
+    fn document_range(&self, id: usize) -> Edit {
+        let index = self.roots.iter().position(|&root| root == id).unwrap();
+        let end = match self.roots.get(index + 1) {
+            Some(_) => self.document_start(index + 1),
+            None => self.source.len(),
+        };
+        (self.document_start(index), end, String::new())
+    }
+
+    fn document_start(&self, index: usize) -> usize {
+        let root = &self.nodes[self.roots[index]];
+        let from = match index {
+            0 => 0,
+            _ => self.nodes[self.roots[index - 1]].end,
+        };
+        let mut start = root.start - self.column(root.start);
+        let mut offset = from;
+        for line in self.source[from..root.start].split('\n') {
+            if line.starts_with("---") && (offset == 0 || self.source.as_bytes()[offset - 1] == b'\n') {
+                start = offset;
+            }
+            offset += line.len() + 1;
+        }
+        start
+    }
+
+    fn column(&self, pos: usize) -> usize {
+        pos - self.source[..pos].rfind('\n').map_or(0, |i| i + 1)
+    }
+}
+
+impl std::fmt::Display for Document {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut out = self.source.clone();
+        for (start, end, text) in self.edits().into_iter().rev() {
+            out.replace_range(start..end, &text);
+        }
+        f.write_str(&out)
+    }
+}
+
+/// Builds the nodes from parser events. Event markers are not always placed
+/// at the start of a node, so spans are completed from the source text.
+struct Builder<'a> {
+    source: &'a str,
+    /// Byte offset of every char, markers count chars
+    offsets: Vec<usize>,
+    nodes: Vec<Node>,
+    roots: Vec<usize>,
+    /// Open collections and, for mappings, the key waiting for its value
+    stack: Vec<(usize, Option<PendingKey>)>,
+    /// Anchored nodes by parser anchor id
+    anchors: HashMap<usize, usize>,
+}
+
+/// A key, its start and the end of its `:` indicator
+type PendingKey = (Yaml, usize, usize);
+
+impl MarkedEventReceiver for Builder<'_> {
+    fn on_event(&mut self, event: Event, mark: Marker) {
+        let pos = self.offsets[mark.index().min(self.offsets.len() - 1)];
+        match event {
+            Event::Scalar(value, style, anchor, _) => {
+                let (start, end) = self.scalar_span(&value, style, pos);
+                let key = match style {
+                    TScalarStyle::Plain => Yaml::from_str(&value),
+                    _ => Yaml::String(value),
+                };
+                let id = self.push(start, end, Kind::Scalar(style));
+                self.anchor(anchor, id);
+                self.attach(id, key);
+            },
+            Event::Alias(anchor) => {
+                let name = self.source[pos + 1..]
+                    .find(|c: char| c.is_whitespace() || ",[]{}".contains(c))
+                    .unwrap_or(self.source.len() - pos - 1);
+                let target = self.anchors.get(&anchor).copied();
+                let id = self.push(pos, pos + 1 + name, Kind::Alias(target));
+                self.attach(id, Yaml::BadValue);
+            },
+            Event::SequenceStart(anchor) => {
+                let id = self.open(pos, Kind::Sequence(Vec::new()), '[');
+                self.anchor(anchor, id);
+            },
+            Event::MappingStart(anchor) => {
+                let id = self.open(pos, Kind::Mapping(Vec::new()), '{');
+                self.anchor(anchor, id);
+            },
+            Event::SequenceEnd | Event::MappingEnd => self.close(pos),
+            _ => {},
+        }
+    }
+}
+
+impl Builder<'_> {
+    fn push(&mut self, start: usize, end: usize, kind: Kind) -> usize {
+        let flow = match self.stack.last() {
+            Some(&(parent, _)) => self.nodes[parent].flow,
+            None => false,
+        };
+        self.nodes.push(Node { start, end, kind, flow, parent: None });
+        self.nodes.len() - 1
+    }
+
+    fn open(&mut self, pos: usize, kind: Kind, bracket: char) -> usize {
+        let id = self.push(pos, pos, kind);
+        self.nodes[id].flow |= self.source[pos..].starts_with(bracket);
+        self.stack.push((id, None));
+        id
+    }
+
+    /// Records `id` as the node of a parser anchor, 0 stands for no anchor
+    fn anchor(&mut self, anchor: usize, id: usize) {
+        if anchor > 0 {
+            self.anchors.insert(anchor, id);
+        }
+    }
+
+    fn close(&mut self, pos: usize) {
+        let (id, _) = self.stack.pop().unwrap();
+        let node = &self.nodes[id];
+        if node.flow && self.source[pos..].starts_with(&[']', '}'][..]) {
+            self.nodes[id].end = pos + 1;
+        } else if let (Some(first), Some(last)) = (node.entries().first(), node.entries().last()) {
+            let span = (first.start, self.nodes[last.node].end);
+            self.nodes[id].start = span.0;
+            self.nodes[id].end = span.1;
+        }
+        self.attach(id, Yaml::BadValue);
+    }
+
+    fn attach(&mut self, id: usize, key: Yaml) {
+        let parent = match self.stack.last() {
+            Some(&(parent, _)) => parent,
+            None => return self.roots.push(id),
+        };
+        let (start, end) = (self.nodes[id].start, self.nodes[id].end);
+        let entry = match self.nodes[parent].kind {
+            Kind::Sequence(_) if self.nodes[parent].flow => {
+                Entry { key: None, start, indicator: start, node: id }
+            },
+            Kind::Sequence(_) => {
+                let dash = self.dash_before(start);
+                Entry { key: None, start: dash, indicator: dash + 1, node: id }
+            },
+            _ => match self.stack.last_mut().unwrap().1.take() {
+                Some((key, start, indicator)) => Entry { key: Some(key), start, indicator, node: id },
+                None => {
+                    let indicator = self.indicator_after(end);
+                    self.stack.last_mut().unwrap().1 = Some((key, start, indicator));
+                    return;
+                },
+            },
+        };
+        if let Kind::Sequence(entries) | Kind::Mapping(entries) = &mut self.nodes[parent].kind {
+            entries.push(entry);
+            let index = entries.len() - 1;
+            self.nodes[id].parent = Some((parent, index));
+        }
+    }
+
+    fn scalar_span(&self, value: &str, style: TScalarStyle, pos: usize) -> (usize, usize) {
+        let rest = &self.source[pos..];
+        match style {
+            TScalarStyle::SingleQuoted => (pos, pos + quoted_len(rest, '\'')),
+            TScalarStyle::DoubleQuoted => (pos, pos + quoted_len(rest, '"')),
+            // Block scalars are reported at their first content line
+            TScalarStyle::Literal | TScalarStyle::Foled => {
+                let start = self.source[..pos].rfind(&['|', '>'][..]).unwrap_or(pos);
+                if value.is_empty() {
+                    let header = self.source[start..].find(char::is_whitespace);
+                    (start, header.map_or(self.source.len(), |i| start + i))
+                } else {
+                    (start, block_scalar_end(self.source, pos))
+                }
+            },
+            // Empty scalars are reported at the next token
+            _ if value == "~" && !rest.starts_with('~') => {
+                let at = match self.stack.last() {
+                    Some((_, Some((_, _, indicator)))) => *indicator,
+                    Some(&(parent, None)) => match self.nodes[parent].kind {
+                        Kind::Sequence(_) => self.dash_before(pos) + 1,
+                        _ => pos,
+                    },
+                    None => pos,
+                };
+                (at, at)
+            },
+            _ => (pos, pos + plain_len(rest, value)),
+        }
+    }
+
+    /// The `-` indicator of the sequence item at `pos`, skipping its anchor and tag
+    fn dash_before(&self, pos: usize) -> usize {
+        let mut at = pos;
+        loop {
+            let head = self.source[..at].trim_end();
+            let token = head.rfind(char::is_whitespace).map_or(0, |i| i + 1);
+            match &head[token..] {
+                "-" => return token,
+                t if t.starts_with('&') || t.starts_with('!') => at = token,
+                _ => return pos,
+            }
+        }
+    }
+
+    /// End of the `:` indicator following a key, or `end` when there is none
+    fn indicator_after(&self, end: usize) -> usize {
+        let rest = &self.source[end..];
+        let trimmed = rest.trim_start();
+        if trimmed.starts_with(':') {
+            end + rest.len() - trimmed.len() + 1
+        } else {
+            end
+        }
+    }
+}
+
+/// Length of the quoted scalar at the start of `rest`
+fn quoted_len(rest: &str, quote: char) -> usize {
+    let mut chars = rest.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        if c == '\\' && quote == '"' {
+            chars.next();
+        } else if c == quote {
+            if quote == '\'' && rest[i + 1..].starts_with('\'') {
+                chars.next();
+            } else {
+                return i + 1;
+            }
+        }
+    }
+    rest.len()
+}
+
+/// Length of the plain scalar at the start of `rest`, line breaks of
+/// multiline scalars are folded into spaces in `value`
+fn plain_len(rest: &str, value: &str) -> usize {
+    let mut source = rest.char_indices().peekable();
+    let mut value = value.chars().peekable();
+    let mut len = 0;
+    while let Some(&v) = value.peek() {
+        match source.peek() {
+            Some(&(i, c)) if c == v => {
+                len = i + c.len_utf8();
+                source.next();
+                value.next();
+            },
+            Some(&(_, c)) if c.is_whitespace() && v.is_whitespace() => {
+                while matches!(source.peek(), Some(&(_, c)) if c.is_whitespace()) {
+                    source.next();
+                }
+                while matches!(value.peek(), Some(c) if c.is_whitespace()) {
+                    value.next();
+                }
+            },
+            _ => break,
+        }
+    }
+    len
+}
+
+/// End of the last line of the block scalar whose content starts at `pos`
+fn block_scalar_end(source: &str, pos: usize) -> usize {
+    let line_start = source[..pos].rfind('\n').map_or(0, |i| i + 1);
+    let indent = pos - line_start;
+    let mut end = pos;
+    let mut offset = line_start;
+    for line in source[line_start..].split('\n') {
+        if !line.trim().is_empty() {
+            if line.len() - line.trim_start_matches(' ').len() < indent {
+                break;
+            }
+            end = offset + line.trim_end().len();
+        }
+        offset += line.len() + 1;
+    }
+    end
+}
+
+/// Writes `value` in flow style. Strings replacing a quoted scalar keep its
+/// quotes, other strings are only quoted when they would not read back as
+/// plain strings.
+pub(crate) fn flow_text(value: &Yaml, style: TScalarStyle) -> String {
+    match value {
+        Yaml::String(s) => match style {
+            TScalarStyle::SingleQuoted if !s.contains(char::is_control) => {
+                format!("'{}'", s.replace('\'', "''"))
+            },
+            TScalarStyle::DoubleQuoted => to_json(value),
+            _ if is_plain(s) => s.clone(),
+            _ => to_json(value),
+        },
+        Yaml::Real(s) => s.clone(),
+        Yaml::Integer(i) => i.to_string(),
+        Yaml::Boolean(b) => b.to_string(),
+        Yaml::Array(array) => {
+            let items: Vec<String> = array.iter().map(|v| flow_text(v, TScalarStyle::Any)).collect();
+            format!("[{}]", items.join(", "))
+        },
+        Yaml::Hash(map) => {
+            let pairs: Vec<String> = map.iter()
+                .map(|(k, v)| format!("{}: {}", flow_text(k, TScalarStyle::Any), flow_text(v, TScalarStyle::Any)))
+                .collect();
+            format!("{{{}}}", pairs.join(", "))
+        },
+        Yaml::Null | Yaml::Alias(_) | Yaml::BadValue => "null".to_owned(),
+    }
+}
+
+fn path_string(path: &[PathSegment]) -> String {
+    path.iter().map(|segment| segment.to_string()).collect::<Vec<_>>().join(".")
+}
+
+fn is_plain(s: &str) -> bool {
+    !s.is_empty()
+        && s.trim() == s
+        && !s.starts_with(|c| "-?:,[]{}#&*!|>'\"%@`".contains(c))
+        && !s.contains(|c: char| c.is_control() || ",[]{}".contains(c))
+        && !s.contains(": ")
+        && !s.contains(" #")
+        && !s.ends_with(':')
+        && Yaml::from_str(s) == Yaml::String(s.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn targets(document: &Document, query: &str) -> Vec<(usize, Vec<PathSegment>)> {
+        let query = parse_query(query).unwrap();
+        Evaluator::new().evaluate_stream(&query, document.docs()).unwrap()
+            .into_iter()
+            .map(|m| (m.document, m.path))
+            .collect()
+    }
+
+    /// Sets through the source and checks that the result loads with `value`
+    /// at every target
+    fn set(source: &str, query: &str, value: &str) -> String {
+        let value = YamlLoader::load_from_str(value).unwrap().remove(0);
+        let mut document = Document::parse(source).unwrap();
+        let targets = targets(&document, query);
+        for (d, path) in targets.iter() {
+            document.set(*d, path, &value).unwrap();
+        }
+        let out = document.to_string();
+
+        let edited = Document::parse(&out).unwrap();
+        for (d, path) in targets.iter() {
+            let node = path.iter().try_fold(&edited.docs()[*d], |node, segment| match segment {
+                PathSegment::Key(key) => node.as_hash()?.get(key),
+                PathSegment::Index(i) => node.as_vec()?.get(*i),
+            });
+            assert_eq!(node, Some(&value), "{}", out);
+        }
+        out
+    }
+
+    /// Deletes through the source and checks that the result still parses
+    fn delete(source: &str, query: &str) -> String {
+        let mut document = Document::parse(source).unwrap();
+        for (d, path) in targets(&document, query) {
+            document.delete(d, &path).unwrap();
+        }
+        let out = document.to_string();
+        assert!(YamlLoader::load_from_str(&out).is_ok(), "{}", out);
+        out
+    }
+
+    const COMPOSE: &str = "# services of the stack
+services:
+  db:   # the database
+    image: \"postgres\"
+    ports: [5432, 5433]
+
+  front:
+    image: 'nginx'
+    scale: 2  # at least two
+    command: |
+      run
+      --fast
+    env:
+    - DEBUG=1
+    - LOG=info
+";
+
+    #[test]
+    fn test_set_scalars() {
+        assert_eq!(set(COMPOSE, "services.db.image", "postgres:13"), COMPOSE.replace("\"postgres\"", "\"postgres:13\""));
+        assert_eq!(set(COMPOSE, "services.*.image", "it's"), COMPOSE
+            .replace("\"postgres\"", "\"it's\"")
+            .replace("'nginx'", "'it''s'"));
+        assert_eq!(set(COMPOSE, "services.front.scale", "3"), COMPOSE.replace("scale: 2", "scale: 3"));
+        assert_eq!(set(COMPOSE, "services.front.command", "serve"), COMPOSE.replace("|\n      run\n      --fast", "serve"));
+        assert_eq!(set(COMPOSE, "services.front.env.1", "'a: b'"), COMPOSE.replace("LOG=info", "\"a: b\""));
+        assert_eq!(set("a:\nb: 1\n", "a", "x"), "a: x\nb: 1\n");
+        assert_eq!(set("- a\n-\n", "1", "b"), "- a\n- b\n");
+    }
+
+    #[test]
+    fn test_set_collections() {
+        assert_eq!(
+            set(COMPOSE, "services.db", "{image: redis, ports: [1, 2]}"),
+            COMPOSE.replace("image: \"postgres\"\n    ports: [5432, 5433]", "{image: redis, ports: [1, 2]}")
+        );
+        assert_eq!(set(COMPOSE, "services.front.env", "[A=1]"), COMPOSE.replace("env:\n    - DEBUG=1\n    - LOG=info", "env: [A=1]"));
+        assert_eq!(set(COMPOSE, "services.db.ports.0", "{}"), COMPOSE.replace("5432,", "{},"));
+
+        // Edits inside a replaced node are dropped
+        let mut document = Document::parse("a: {b: {c: 1}}\n").unwrap();
+        let path = vec![
+            PathSegment::Key(Yaml::String("a".to_owned())),
+            PathSegment::Key(Yaml::String("b".to_owned())),
+        ];
+        document.set(0, &path, &Yaml::Integer(1)).unwrap();
+        document.set(0, &path[..1], &Yaml::Integer(2)).unwrap();
+        assert_eq!(document.to_string(), "a: 2\n");
+    }
+
+    #[test]
+    fn test_delete_block() {
+        assert_eq!(delete(COMPOSE, "services.front.scale"), COMPOSE.replace("    scale: 2  # at least two\n", ""));
+        assert_eq!(delete(COMPOSE, "services.front.command"), COMPOSE.replace("    command: |\n      run\n      --fast\n", ""));
+        assert_eq!(delete(COMPOSE, "services.front.env.0"), COMPOSE.replace("    - DEBUG=1\n", ""));
+        assert_eq!(delete(COMPOSE, "services.front.env.*"), COMPOSE.replace("\n    - DEBUG=1\n    - LOG=info", " []"));
+        assert_eq!(delete(COMPOSE, "services.db.*"), COMPOSE.replace("image: \"postgres\"\n    ports: [5432, 5433]", "{}"));
+        assert_eq!(delete("- name: db\n  scale: 0\n- name: front\n", "*.name"), "- scale: 0\n- {}\n");
+    }
+
+    #[test]
+    fn test_delete_flow() {
+        assert_eq!(delete("l: [a, b, c]\n", "l.0"), "l: [b, c]\n");
+        assert_eq!(delete("l: [a, b, c]\n", "l.1"), "l: [a, c]\n");
+        assert_eq!(delete("l: [a, b, c]\n", "l.2"), "l: [a, b]\n");
+        let list = "l: [{n: a}, {n: b}, {n: c}]\n";
+        assert_eq!(delete(list, "l.*(n != 'b')"), "l: [{n: b}]\n");
+        assert_eq!(delete(list, "l.*(n != 'a')"), "l: [{n: a}]\n");
+        assert_eq!(delete("l: [a, b, c]\n", "l.*"), "l: []\n");
+        assert_eq!(delete("m: {a: 1, b: 2}  # kept\n", "m.b"), "m: {a: 1}  # kept\n");
+    }
+
+    #[test]
+    fn test_delete_documents() {
+        let stream = "# header\nkind: Secret\n---\nkind: Service\n--- # last\nkind: Secret\n";
+        assert_eq!(delete(stream, "#(kind == 'Secret')"), "# header\n---\nkind: Service\n");
+        assert_eq!(delete(stream, "#1"), "# header\nkind: Secret\n--- # last\nkind: Secret\n");
+    }
+
+    #[test]
+    fn test_not_in_source() {
+        let mut document = Document::parse("a: &x {b: 1}\nc: *x\n").unwrap();
+        let path = vec![
+            PathSegment::Key(Yaml::String("c".to_owned())),
+            PathSegment::Key(Yaml::String("b".to_owned())),
+        ];
+        assert_eq!(
+            document.set(0, &path, &Yaml::Integer(2)),
+            Err(EditError::NotInSource { document: 0, path: "c.b".to_owned() })
+        );
+        assert_eq!(document.set(0, &path[..1], &Yaml::Integer(2)), Ok(()));
+        assert_eq!(document.to_string(), "a: &x {b: 1}\nc: 2\n");
+    }
+
+    #[test]
+    fn test_aliased_anchor() {
+        let source = "a: &x {b: &y 1}\nc: *x\nd: *y\n";
+        let key = |k: &str| PathSegment::Key(Yaml::String(k.to_owned()));
+        let aliased = |path: &str, anchor: &str| Err(EditError::AliasedAnchor {
+            document: 0,
+            path: path.to_owned(),
+            anchor: anchor.to_owned(),
+        });
+
+        let mut document = Document::parse(source).unwrap();
+        assert_eq!(document.delete(0, &[key("a")]), aliased("a", "x"));
+        assert_eq!(document.delete(0, &[key("a"), key("b")]), aliased("a.b", "y"));
+        assert_eq!(document.set(0, &[key("a")], &Yaml::Integer(2)), aliased("a", "y"));
+        assert_eq!(document.to_string(), source);
+
+        // Once the aliases are gone the anchors can go too
+        assert_eq!(document.delete(0, &[key("d")]), Ok(()));
+        assert_eq!(document.set(0, &[key("a")], &Yaml::Integer(2)), Ok(()));
+        assert_eq!(document.delete(0, &[key("c")]), Ok(()));
+        assert_eq!(document.delete(0, &[key("a")]), Ok(()));
+        let out = document.to_string();
+        assert_eq!(out, "{}\n");
+        assert_eq!(YamlLoader::load_from_str(&out).unwrap(), YamlLoader::load_from_str("{}").unwrap());
+
+        // Aliases inside the deleted node go with it
+        let mut document = Document::parse("a: [&x 1, *x]\nb: 2\n").unwrap();
+        assert_eq!(document.delete(0, &[key("a")]), Ok(()));
+        let out = document.to_string();
+        assert_eq!(YamlLoader::load_from_str(&out).unwrap(), YamlLoader::load_from_str("b: 2").unwrap(), "{}", out);
+    }
+
+    #[test]
+    fn test_anchor() {
+        let document = Document::parse("
+defaults: &defaults
+  image: nginx
+  scale: 1
+extra: &extra {debug: true, scale: 5}
+ports: &ports [80, 443]
+services:
+  web:
+    <<: *defaults
+    scale: 3
+  api:
+    <<: [*extra, *defaults]
+    ports: *ports
+").unwrap();
+        let origins = |query: &str| -> Vec<(String, Option<String>)> {
+            let pipeline = parse_pipeline(query).unwrap();
+            document.evaluate(&Evaluator::new(), &pipeline).unwrap()
+                .into_iter()
+                .map(|m| (m.path_string(), m.anchor))
+                .collect()
+        };
+        let origin = |path: &str, anchor: Option<&str>| (path.to_owned(), anchor.map(str::to_owned));
+        assert_eq!(origins("services.web.*"), vec![
+            origin("services.web.image", Some("defaults")),
+            origin("services.web.scale", None),
+        ]);
+        assert_eq!(origins("services.api.*"), vec![
+            origin("services.api.debug", Some("extra")),
+            origin("services.api.scale", Some("extra")),
+            origin("services.api.image", Some("defaults")),
+            origin("services.api.ports", Some("ports")),
+        ]);
+        assert_eq!(origins("services.api.ports[1]"), vec![origin("services.api.ports.1", Some("ports"))]);
+        assert_eq!(origins("defaults.image"), vec![origin("defaults.image", None)]);
+
+        let raw = Evaluator::new().raw(true);
+        let paths: Vec<(String, Option<String>)> = document.evaluate(&raw, &parse_pipeline("services.web.*").unwrap()).unwrap()
+            .into_iter()
+            .map(|m| (m.path_string(), m.anchor))
+            .collect();
+        assert_eq!(paths, vec![origin("services.web.\\<\\<", Some("defaults")), origin("services.web.scale", None)]);
+
+        // Inherited values are not part of the source
+        let mut document = document;
+        let path = targets(&document, "services.web.image").remove(0).1;
+        assert!(document.set(0, &path, &Yaml::Null).is_err());
+    }
+}
@@ -0,0 +1,1551 @@
+use super::*;
+use yaml_rust::Yaml;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use super::functions::builtin;
+
+/// Runs `query` against `doc` and returns every matching node in document
+/// order. A query calling an unknown function matches nothing, use
+/// `Evaluator::evaluate` to get the error instead.
+pub fn evaluate<'a>(query: &Query, doc: &'a Yaml) -> Vec<Match<'a>> {
+    // Lenient evaluation only fails on unknown functions
+    Evaluator::new().evaluate(query, doc).unwrap_or_default()
+}
+
+/// The node a condition is checked against and its ancestors, from the
+/// document root to its parent
+#[derive(Clone, Copy)]
+struct Scope<'s> {
+    node: &'s Yaml,
+    ancestors: &'s [&'s Yaml],
+}
+
+/// Query evaluation settings
+#[derive(Clone, Default)]
+pub struct Evaluator {
+    strict: bool,
+    raw: bool,
+    functions: HashMap<String, Function>,
+}
+
+impl std::fmt::Debug for Evaluator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut functions: Vec<&String> = self.functions.keys().collect();
+        functions.sort();
+        f.debug_struct("Evaluator")
+            .field("strict", &self.strict)
+            .field("raw", &self.raw)
+            .field("functions", &functions)
+            .finish()
+    }
+}
+
+impl Evaluator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Report comparisons between incompatible types as errors instead of
+    /// treating them as false
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Query the structure as written, where `<<` merge keys are plain keys
+    /// instead of adding the entries of the merged mappings
+    pub fn raw(mut self, raw: bool) -> Self {
+        self.raw = raw;
+        self
+    }
+
+    /// Makes `function` callable as `name` in conditions, replacing a
+    /// built-in function of the same name
+    pub fn function<F>(mut self, name: &str, function: F) -> Self
+    where
+        F: Fn(&[Yaml]) -> Option<Yaml> + Send + Sync + 'static,
+    {
+        self.functions.insert(name.to_owned(), std::sync::Arc::new(function));
+        self
+    }
+
+    /// Runs `query` against `doc` and returns every matching node in document order
+    pub fn evaluate<'a>(&self, query: &Query, doc: &'a Yaml) -> Result<Vec<Match<'a>>, EvalError> {
+        self.evaluate_stream(query, std::slice::from_ref(doc))
+    }
+
+    /// Runs `query` against every document of a stream selected by `query.document`
+    pub fn evaluate_stream<'a>(&self, query: &Query, docs: &'a [Yaml]) -> Result<Vec<Match<'a>>, EvalError> {
+        let mut matches = Vec::new();
+        for (i, doc) in docs.iter().enumerate() {
+            if self.select_document(query, i, doc)? {
+                matches.extend(self.evaluate_from(query, i, doc, doc, Vec::new())?);
+            }
+        }
+        Ok(matches)
+    }
+
+    /// Runs the stages of `pipeline` in turn, each one on the matches of the
+    /// previous one. The first stage runs on the documents of the stream.
+    ///
+    /// Values built by a projection are queried as standalone documents and
+    /// what is found in them keeps the path of the projected match.
+    /// Aggregates run once over the matches of all documents.
+    pub fn evaluate_pipeline<'a>(&self, pipeline: &Pipeline, docs: &'a [Yaml]) -> Result<Vec<Match<'a>>, EvalError> {
+        let matches: Vec<Match<'a>> = docs.iter().enumerate()
+            .map(|(document, doc)| Match { document, path: Vec::new(), value: Cow::Borrowed(doc), anchor: None })
+            .collect();
+        self.run_stages(&pipeline.stages, docs, matches)
+    }
+
+    /// Runs `stages` in turn starting from `matches`, whose document indices
+    /// refer to `docs`
+    pub fn run_stages<'a>(&self, stages: &[Stage], docs: &'a [Yaml], mut matches: Vec<Match<'a>>) -> Result<Vec<Match<'a>>, EvalError> {
+        for stage in stages.iter() {
+            if let Stage::Aggregate(aggregate) = stage {
+                matches = self.aggregate(aggregate, docs, matches)?;
+                continue;
+            }
+            let mut next = Vec::new();
+            for m in matches {
+                let root = &docs[m.document];
+                match (stage, &m.value) {
+                    (Stage::Query(query), Cow::Borrowed(node)) => {
+                        if self.select_document(query, m.document, root)? {
+                            next.extend(self.evaluate_from(query, m.document, root, node, m.path)?);
+                        }
+                    },
+                    (Stage::Query(query), Cow::Owned(value)) => {
+                        if self.select_document(query, m.document, root)? {
+                            for found in self.evaluate_from(query, m.document, value, value, Vec::new())? {
+                                next.push(Match {
+                                    document: m.document,
+                                    path: m.path.clone(),
+                                    value: Cow::Owned(found.value.into_owned()),
+                                    anchor: None,
+                                });
+                            }
+                        }
+                    },
+                    (Stage::Select(condition), _) => {
+                        let ancestors = match_ancestors(docs, &m, self.raw);
+                        if self.check_list(Scope { node: &m.value, ancestors: &ancestors }, condition)? {
+                            next.push(m);
+                        }
+                    },
+                    (Stage::Aggregate(_), _) => unreachable!(),
+                }
+            }
+            if let Stage::Query(query) = stage {
+                if query.path.iter().any(|entry| entry.recursive) {
+                    // Nested inputs reach the same node through `..` from each of them
+                    let mut seen = std::collections::HashSet::new();
+                    next.retain(|m| matches!(m.value, Cow::Owned(_)) || seen.insert((m.document, m.path.clone())));
+                }
+            }
+            matches = next;
+        }
+        Ok(matches)
+    }
+
+    /// Computes `aggregate` over the whole match set. Count, sum, average and
+    /// groups are new values with an empty path, while the other aggregates
+    /// select or reorder the matches themselves.
+    fn aggregate<'a>(&self, aggregate: &Aggregate, docs: &'a [Yaml], mut matches: Vec<Match<'a>>) -> Result<Vec<Match<'a>>, EvalError> {
+        let document = matches.first().map_or(0, |m| m.document);
+        let built = |value| vec![Match { document, path: Vec::new(), value: Cow::Owned(value), anchor: None }];
+        Ok(match aggregate {
+            Aggregate::Count => built(Yaml::Integer(matches.len() as i64)),
+            Aggregate::Sum | Aggregate::Avg => {
+                let mut numbers = Vec::new();
+                for m in matches.iter() {
+                    match &*m.value {
+                        Yaml::Integer(_) | Yaml::Real(_) => numbers.push(&*m.value),
+                        // Missing values and nulls do not count, as in SQL
+                        Yaml::Null | Yaml::BadValue => {},
+                        value if self.strict => return Err(EvalError::InvalidArguments {
+                            function: aggregate.to_string(),
+                            arguments: vec![type_name(value)],
+                        }),
+                        _ => {},
+                    }
+                }
+                let integers: Option<Vec<i64>> = numbers.iter().map(|n| n.as_i64()).collect();
+                let total = integers.and_then(|integers| {
+                    integers.into_iter().try_fold(0i64, |total, i| total.checked_add(i))
+                });
+                let sum: f64 = numbers.iter().filter_map(|n| number(n)).sum();
+                // Debug formatting keeps the decimal point of whole floats
+                match aggregate {
+                    Aggregate::Sum => built(match total {
+                        Some(total) => Yaml::Integer(total),
+                        None => Yaml::Real(format!("{:?}", sum)),
+                    }),
+                    _ if numbers.is_empty() => Vec::new(),
+                    _ => built(Yaml::Real(format!("{:?}", sum / numbers.len() as f64))),
+                }
+            },
+            Aggregate::Min | Aggregate::Max => {
+                let sign = if *aggregate == Aggregate::Min { CompareSign::Lt } else { CompareSign::Gt };
+                let mut best: Option<usize> = None;
+                for (i, m) in matches.iter().enumerate() {
+                    if let Yaml::Null | Yaml::BadValue = *m.value {
+                        continue;
+                    }
+                    let current = match best {
+                        Some(current) => &matches[current].value,
+                        None => {
+                            best = Some(i);
+                            continue;
+                        },
+                    };
+                    match compare(&m.value, &sign, current) {
+                        Some(true) => best = Some(i),
+                        Some(false) => {},
+                        None if self.strict => return Err(EvalError::TypeMismatch {
+                            left: type_name(&m.value),
+                            sign,
+                            right: type_name(current),
+                        }),
+                        None => {},
+                    }
+                }
+                best.map(|best| matches.swap_remove(best)).into_iter().collect()
+            },
+            Aggregate::Unique => {
+                let mut unique: Vec<Match<'a>> = Vec::new();
+                for m in matches {
+                    if !unique.iter().any(|u| compare(&u.value, &CompareSign::Eq, &m.value) == Some(true)) {
+                        unique.push(m);
+                    }
+                }
+                unique
+            },
+            Aggregate::SortBy(key) => {
+                let mut keyed = Vec::with_capacity(matches.len());
+                for m in matches {
+                    let ancestors = match_ancestors(docs, &m, self.raw);
+                    keyed.push((self.resolve(Scope { node: &m.value, ancestors: &ancestors }, key)?, m));
+                }
+                keyed.sort_by(|(a, _), (b, _)| total_order(a, b));
+                keyed.into_iter().map(|(_, m)| m).collect()
+            },
+            Aggregate::GroupBy(key) => {
+                let mut groups = yaml_rust::yaml::Hash::new();
+                for m in matches.iter() {
+                    let ancestors = match_ancestors(docs, m, self.raw);
+                    let group = match self.resolve(Scope { node: &m.value, ancestors: &ancestors }, key)? {
+                        Yaml::BadValue => Yaml::Null,
+                        group => group,
+                    };
+                    if let Yaml::Array(members) = groups.entry(group).or_insert_with(|| Yaml::Array(Vec::new())) {
+                        members.push(m.value.clone().into_owned());
+                    }
+                }
+                built(Yaml::Hash(groups))
+            },
+            Aggregate::GroupByDocument => {
+                let mut groups = yaml_rust::yaml::Hash::new();
+                for m in matches.iter() {
+                    let group = Yaml::Integer(m.document as i64);
+                    if let Yaml::Array(members) = groups.entry(group).or_insert_with(|| Yaml::Array(Vec::new())) {
+                        members.push(m.value.clone().into_owned());
+                    }
+                }
+                built(Yaml::Hash(groups))
+            },
+        })
+    }
+
+    /// Runs `query` from `node`, found at `path` in the document `root`
+    fn evaluate_from<'a>(
+        &self,
+        query: &Query,
+        document: usize,
+        root: &'a Yaml,
+        node: &'a Yaml,
+        mut path: Vec<PathSegment>,
+    ) -> Result<Vec<Match<'a>>, EvalError> {
+        let mut matches = Vec::new();
+        let mut stack = ancestors(root, &path, self.raw);
+        self.find(document, node, &query.path, &mut path, &mut stack, &mut matches)?;
+        if query.path.iter().any(|entry| entry.recursive) {
+            // Nested `..` segments reach the same node along several routes
+            let mut seen = std::collections::HashSet::new();
+            matches.retain(|m| seen.insert(m.path.clone()));
+        }
+        if query.filter.is_some() || query.projection.is_some() {
+            let mut kept = Vec::with_capacity(matches.len());
+            for mut m in matches {
+                let ancestors = ancestors(root, &m.path, self.raw);
+                let scope = Scope { node: &m.value, ancestors: &ancestors };
+                if let Some(filter) = &query.filter {
+                    if !self.check_condition(scope, filter)? {
+                        continue;
+                    }
+                }
+                if let Some(fields) = &query.projection {
+                    m.value = Cow::Owned(self.project(scope, fields)?);
+                }
+                kept.push(m);
+            }
+            matches = kept;
+        }
+        Ok(matches)
+    }
+
+    fn select_document(&self, query: &Query, index: usize, doc: &Yaml) -> Result<bool, EvalError> {
+        let entry = match &query.document {
+            Some(entry) => entry,
+            None => return Ok(true),
+        };
+        if let Some(key) = entry.key.as_deref() {
+            if key != "*" && key.parse::<usize>() != Ok(index) {
+                return Ok(false);
+            }
+        }
+        self.check(Scope { node: doc, ancestors: &[] }, &entry.condition)
+    }
+
+    fn find<'a>(
+        &self,
+        document: usize,
+        doc: &'a Yaml,
+        path: &[PathEntry],
+        sp: &mut Vec<PathSegment>,
+        ancestors: &mut Vec<&'a Yaml>,
+        matches: &mut Vec<Match<'a>>,
+    ) -> Result<(), EvalError> {
+        let entry = match path.first() {
+            Some(entry) => entry,
+            None => {
+                matches.push(Match {
+                    document,
+                    path: sp.clone(),
+                    value: Cow::Borrowed(doc),
+                    anchor: None,
+                });
+                return Ok(());
+            }
+        };
+        // Children are visited with `doc` as their parent
+        ancestors.push(doc);
+
+        match doc {
+            Yaml::Array(ref array) if entry.subscript.is_some() => {
+                for i in select(entry.subscript.as_ref().unwrap(), array.len()) {
+                    let v = &array[i];
+                    if self.check(Scope { node: v, ancestors }, &entry.condition)? {
+                        sp.push(PathSegment::Index(i));
+                        self.find(document, v, &path[1..], sp, ancestors, matches)?;
+                        sp.pop();
+                    }
+                }
+            },
+            _ if entry.subscript.is_some() => {},
+            Yaml::Array(ref array) if entry.literal.is_some() || entry.pattern.is_some() => {
+                // Sequences are indexed by integer keys only
+                let intkey = match entry.literal {
+                    Some(Yaml::Integer(i)) if i >= 0 => Some(i as usize),
+                    _ => None,
+                };
+                if let Some((intkey, v)) = intkey.and_then(|i| Some((i, array.get(i)?))) {
+                    if self.check(Scope { node: v, ancestors }, &entry.condition)? {
+                        sp.push(PathSegment::Index(intkey));
+                        self.find(document, v, &path[1..], sp, ancestors, matches)?;
+                        sp.pop();
+                    }
+                }
+            },
+            Yaml::Array(ref array) => {
+                for (i, v) in array.iter().enumerate() {
+                    if self.check(Scope { node: v, ancestors }, &entry.condition)? {
+                        sp.push(PathSegment::Index(i));
+                        self.find(document, v, &path[1..], sp, ancestors, matches)?;
+                        sp.pop();
+                    }
+                }
+            },
+            Yaml::Hash(ref map) => {
+                for (k, v) in entries(map, self.raw) {
+                    let matched = match (&entry.pattern, &entry.literal) {
+                        (Some(pattern), _) => match scalar_text(k) {
+                            Some(text) => pattern.is_match(&text),
+                            None => false,
+                        },
+                        (None, Some(literal)) => k == literal,
+                        (None, None) => true,
+                    };
+                    if matched && self.check(Scope { node: v, ancestors }, &entry.condition)? {
+                        sp.push(PathSegment::Key(k.clone()));
+                        self.find(document, v, &path[1..], sp, ancestors, matches)?;
+                        sp.pop();
+                    }
+                }
+            },
+            _ => {}
+        }
+
+        if entry.recursive {
+            // The loader copies aliased nodes and loads an alias inside its
+            // own anchor as a bad value, so the descent always ends
+            let children: Vec<(PathSegment, &Yaml)> = match doc {
+                Yaml::Array(array) => array.iter().enumerate().map(|(i, v)| (PathSegment::Index(i), v)).collect(),
+                Yaml::Hash(map) => entries(map, self.raw).into_iter().map(|(k, v)| (PathSegment::Key(k.clone()), v)).collect(),
+                _ => Vec::new(),
+            };
+            for (segment, child) in children {
+                sp.push(segment);
+                self.find(document, child, path, sp, ancestors, matches)?;
+                sp.pop();
+            }
+        }
+        ancestors.pop();
+        Ok(())
+    }
+
+    fn check(&self, scope: Scope<'_>, condition: &Option<Vec<ConditionListItem>>) -> Result<bool, EvalError> {
+        match condition {
+            Some(list) => self.check_list(scope, list),
+            None => Ok(true),
+        }
+    }
+
+    /// Evaluates a condition list where `!` binds tighter than `&&`, `&&` tighter
+    /// than `^` and `^` tighter than `||`. Operands of `&&` and `||` are evaluated
+    /// lazily, so evaluation stops as soon as the result is known.
+    fn check_list(&self, scope: Scope<'_>, list: &[ConditionListItem]) -> Result<bool, EvalError> {
+        for xor_list in split(list, Relation::Or) {
+            let mut value = false;
+            for and_list in split(xor_list, Relation::Xor) {
+                value ^= self.check_and(scope, and_list)?;
+            }
+            if value {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn check_and(&self, scope: Scope<'_>, list: &[ConditionListItem]) -> Result<bool, EvalError> {
+        for operand in split(list, Relation::And) {
+            if !self.check_operand(scope, operand)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Evaluates a single operand with its leading `!` prefixes
+    fn check_operand(&self, scope: Scope<'_>, operand: &[ConditionListItem]) -> Result<bool, EvalError> {
+        match operand.split_first() {
+            Some((ConditionListItem::Not, rest)) => Ok(!self.check_operand(scope, rest)?),
+            Some((ConditionListItem::Group(group), _)) => self.check_list(scope, group),
+            Some((ConditionListItem::Condition(condition), _)) => self.check_condition(scope, condition),
+            Some((ConditionListItem::Statement(statement), _)) => Ok(truthy(&self.resolve(scope, statement)?)),
+            Some((ConditionListItem::Relation(_), _)) | None => Ok(false),
+        }
+    }
+
+    fn check_condition(&self, scope: Scope<'_>, condition: &Condition) -> Result<bool, EvalError> {
+        let left = self.resolve(scope, &condition.left)?;
+        let right = self.resolve(scope, &condition.right)?;
+
+        let result = match &condition.right {
+            Statement::Pattern(pattern) => {
+                left.as_str().map(|s| pattern.is_match(s) != (condition.sign == CompareSign::NotMatch))
+            },
+            _ => compare(&left, &condition.sign, &right),
+        };
+        match result {
+            Some(result) => Ok(result),
+            None if self.strict => Err(EvalError::TypeMismatch {
+                left: type_name(&left),
+                sign: condition.sign.clone(),
+                right: type_name(&right),
+            }),
+            None => Ok(condition.sign == CompareSign::Ne || condition.sign == CompareSign::NotMatch),
+        }
+    }
+
+    /// Builds the mapping of a projection, missing values become null
+    fn project(&self, scope: Scope<'_>, fields: &[Field]) -> Result<Yaml, EvalError> {
+        let mut map = yaml_rust::yaml::Hash::new();
+        for field in fields {
+            let value = match self.resolve(scope, &field.value)? {
+                Yaml::BadValue => Yaml::Null,
+                value => value,
+            };
+            map.insert(Yaml::String(field.name.clone()), value);
+        }
+        Ok(Yaml::Hash(map))
+    }
+
+    fn resolve(&self, scope: Scope<'_>, statement: &Statement) -> Result<Yaml, EvalError> {
+        Ok(match statement {
+            Statement::Boolean(b) => Yaml::Boolean(*b),
+            Statement::Integer(i) => Yaml::Integer(*i),
+            Statement::String(s) => Yaml::String(s.clone()),
+            Statement::Double(f) => Yaml::Real(f.to_string()),
+            Statement::None => Yaml::Null,
+            Statement::Path(path) => get(scope.node, path, self.raw).clone(),
+            Statement::Root(path) => get(scope.ancestors.first().copied().unwrap_or(scope.node), path, self.raw).clone(),
+            Statement::Parent(levels, path) => match scope.ancestors.len().checked_sub(*levels) {
+                Some(i) => get(scope.ancestors[i], path, self.raw).clone(),
+                None => Yaml::BadValue,
+            },
+            Statement::List(items) => Yaml::Array(
+                items.iter().map(|item| self.resolve(scope, item)).collect::<Result<_, _>>()?
+            ),
+            Statement::Pattern(pattern) => Yaml::String(pattern.as_str().to_owned()),
+            Statement::Function { name, args } => {
+                let builtin = builtin(name);
+                let function: &dyn Fn(&[Yaml]) -> Option<Yaml> = match (self.functions.get(name), &builtin) {
+                    (Some(function), _) => function.as_ref(),
+                    (None, Some(function)) => function,
+                    (None, None) => return Err(EvalError::UnknownFunction(name.clone())),
+                };
+                let args = args.iter().map(|arg| self.resolve(scope, arg)).collect::<Result<Vec<_>, _>>()?;
+                match function(&args) {
+                    Some(value) => value,
+                    None if self.strict => return Err(EvalError::InvalidArguments {
+                        function: name.clone(),
+                        arguments: args.iter().map(type_name).collect(),
+                    }),
+                    // A failed call yields a missing value, like a missing path
+                    None => Yaml::BadValue,
+                }
+            },
+        })
+    }
+}
+
+fn split(list: &[ConditionListItem], relation: Relation) -> impl Iterator<Item = &[ConditionListItem]> {
+    list.split(move |item| match item {
+        ConditionListItem::Relation(r) => *r == relation,
+        _ => false,
+    })
+}
+
+/// Compares two values, `None` when their types are not comparable with `sign`.
+///
+/// Integers and doubles are compared numerically, strings lexicographically
+/// and booleans with `false < true`. A missing value is treated as null and
+/// null may be tested for equality with any type. Sequences and mappings only
+/// support equality with values of the same kind.
+///
+/// The string operators only apply to strings, except that `*=` also looks
+/// for an element of a sequence on the left and `in` for one on the right.
+fn compare(l: &Yaml, sign: &CompareSign, r: &Yaml) -> Option<bool> {
+    let contains = |items: &[Yaml], value: &Yaml| {
+        items.iter().any(|item| compare(item, &CompareSign::Eq, value) == Some(true))
+    };
+    match sign {
+        CompareSign::Match | CompareSign::NotMatch => {
+            let pattern = Pattern::new(r.as_str()?).ok()?;
+            return Some(pattern.is_match(l.as_str()?) != (*sign == CompareSign::NotMatch));
+        },
+        CompareSign::StartsWith => return Some(l.as_str()?.starts_with(r.as_str()?)),
+        CompareSign::EndsWith => return Some(l.as_str()?.ends_with(r.as_str()?)),
+        CompareSign::Contains => return match l {
+            Yaml::Array(items) => Some(contains(items, r)),
+            _ => Some(l.as_str()?.contains(r.as_str()?)),
+        },
+        CompareSign::In => return match r {
+            Yaml::Array(items) => Some(contains(items, l)),
+            _ => None,
+        },
+        _ => {},
+    }
+
+    let ordering = match (l, r) {
+        (Yaml::Integer(a), Yaml::Integer(b)) => a.cmp(b),
+        (Yaml::Integer(_), Yaml::Real(_)) |
+        (Yaml::Real(_), Yaml::Integer(_)) |
+        (Yaml::Real(_), Yaml::Real(_)) => {
+            match number(l)?.partial_cmp(&number(r)?) {
+                Some(ordering) => ordering,
+                // NaN is not equal to anything
+                None => return Some(*sign == CompareSign::Ne),
+            }
+        },
+        (Yaml::String(a), Yaml::String(b)) => a.cmp(b),
+        (Yaml::Boolean(a), Yaml::Boolean(b)) => a.cmp(b),
+        (Yaml::Null, Yaml::Null) |
+        (Yaml::Null, Yaml::BadValue) |
+        (Yaml::BadValue, Yaml::Null) |
+        (Yaml::BadValue, Yaml::BadValue) => Ordering::Equal,
+        (Yaml::Null, _) | (Yaml::BadValue, _) |
+        (_, Yaml::Null) | (_, Yaml::BadValue) |
+        (Yaml::Array(_), Yaml::Array(_)) |
+        (Yaml::Hash(_), Yaml::Hash(_)) => {
+            return match sign {
+                CompareSign::Eq => Some(l == r),
+                CompareSign::Ne => Some(l != r),
+                _ => None,
+            };
+        },
+        _ => return None,
+    };
+
+    Some(match sign {
+        CompareSign::Eq => ordering == Ordering::Equal,
+        CompareSign::Ne => ordering != Ordering::Equal,
+        CompareSign::Gt => ordering == Ordering::Greater,
+        CompareSign::Lt => ordering == Ordering::Less,
+        CompareSign::Ge => ordering != Ordering::Less,
+        CompareSign::Le => ordering != Ordering::Greater,
+        _ => unreachable!(),
+    })
+}
+
+/// Total order of values for sorting: missing values and nulls first, then
+/// booleans, numbers, strings, sequences and mappings
+fn total_order(l: &Yaml, r: &Yaml) -> Ordering {
+    fn rank(value: &Yaml) -> u8 {
+        match value {
+            Yaml::BadValue | Yaml::Null => 0,
+            Yaml::Boolean(_) => 1,
+            Yaml::Integer(_) | Yaml::Real(_) => 2,
+            Yaml::String(_) => 3,
+            Yaml::Array(_) => 4,
+            Yaml::Hash(_) => 5,
+            Yaml::Alias(_) => 6,
+        }
+    }
+    match (l, r) {
+        (Yaml::Integer(a), Yaml::Integer(b)) => a.cmp(b),
+        (Yaml::Integer(_), Yaml::Real(_)) |
+        (Yaml::Real(_), Yaml::Integer(_)) |
+        (Yaml::Real(_), Yaml::Real(_)) => {
+            number(l).unwrap_or(f64::NAN).total_cmp(&number(r).unwrap_or(f64::NAN))
+        },
+        (Yaml::String(a), Yaml::String(b)) => a.cmp(b),
+        (Yaml::Boolean(a), Yaml::Boolean(b)) => a.cmp(b),
+        _ if rank(l) == rank(r) => l.cmp(r),
+        _ => rank(l).cmp(&rank(r)),
+    }
+}
+
+fn number(value: &Yaml) -> Option<f64> {
+    match value {
+        Yaml::Integer(i) => Some(*i as f64),
+        Yaml::Real(_) => value.as_f64(),
+        _ => None,
+    }
+}
+
+pub(crate) fn type_name(value: &Yaml) -> &'static str {
+    match value {
+        Yaml::Real(_) => "double",
+        Yaml::Integer(_) => "integer",
+        Yaml::String(_) => "string",
+        Yaml::Boolean(_) => "boolean",
+        Yaml::Array(_) => "sequence",
+        Yaml::Hash(_) => "mapping",
+        Yaml::Alias(_) => "alias",
+        Yaml::Null => "null",
+        Yaml::BadValue => "missing value",
+    }
+}
+
+/// Meaning of a bare statement used as a predicate: the value exists and is
+/// neither null nor `false`. Zero, empty strings and empty collections are true.
+fn truthy(value: &Yaml) -> bool {
+    !matches!(value, Yaml::BadValue | Yaml::Null | Yaml::Boolean(false))
+}
+
+/// Indices of the elements of a sequence of length `len` selected by
+/// `subscript`, in selection order. Indices out of range are skipped and
+/// slice bounds are clamped.
+fn select(subscript: &Subscript, len: usize) -> Vec<usize> {
+    let len = len as i64;
+    let resolve = |i: i64| if i < 0 { i + len } else { i };
+    match subscript {
+        Subscript::Index(i) => select(&Subscript::List(vec![*i]), len as usize),
+        Subscript::List(list) => list.iter()
+            .map(|&i| resolve(i))
+            .filter(|&i| i >= 0 && i < len)
+            .map(|i| i as usize)
+            .collect(),
+        Subscript::Slice { start, end, step } => {
+            let step = step.unwrap_or(1);
+            if step == 0 {
+                return Vec::new();
+            }
+            let (first, last) = if step > 0 { (0, len) } else { (-1, len - 1) };
+            let clamp = |i: i64| resolve(i).max(first).min(last);
+            let mut i = start.map_or(if step > 0 { 0 } else { len - 1 }, clamp);
+            let end = end.map_or(if step > 0 { len } else { -1 }, clamp);
+
+            let mut indices = Vec::new();
+            while (step > 0 && i < end) || (step < 0 && i > end) {
+                indices.push(i as usize);
+                i = match i.checked_add(step) {
+                    Some(i) => i,
+                    None => break,
+                };
+            }
+            indices
+        },
+    }
+}
+
+/// Ancestors of a matched node, none for values built by the evaluator
+fn match_ancestors<'a>(docs: &'a [Yaml], m: &Match<'a>, raw: bool) -> Vec<&'a Yaml> {
+    match m.value {
+        Cow::Borrowed(_) => ancestors(&docs[m.document], &m.path, raw),
+        Cow::Owned(_) => Vec::new(),
+    }
+}
+
+/// Nodes from `doc` to the parent of the node at `path`
+fn ancestors<'a>(doc: &'a Yaml, path: &[PathSegment], raw: bool) -> Vec<&'a Yaml> {
+    if path.is_empty() {
+        return Vec::new();
+    }
+    let mut nodes = vec![doc];
+    for segment in path.iter().take(path.len().saturating_sub(1)) {
+        let child = match (nodes[nodes.len() - 1], segment) {
+            (Yaml::Hash(map), PathSegment::Key(key)) => lookup(map, key, raw),
+            (Yaml::Array(array), PathSegment::Index(i)) => array.get(*i),
+            _ => None,
+        };
+        match child {
+            Some(child) => nodes.push(child),
+            None => break,
+        }
+    }
+    nodes
+}
+
+/// Text of a scalar mapping key as patterns see it
+fn scalar_text(key: &Yaml) -> Option<String> {
+    match key {
+        Yaml::String(s) | Yaml::Real(s) => Some(s.clone()),
+        Yaml::Integer(i) => Some(i.to_string()),
+        Yaml::Boolean(b) => Some(b.to_string()),
+        Yaml::Null => Some("null".to_owned()),
+        _ => None,
+    }
+}
+
+/// Key of YAML merges, `<<: *defaults` inherits the entries of `defaults`
+const MERGE_KEY: &str = "<<";
+
+/// Mappings merged into `map` by its `<<` key, a mapping or a sequence of them
+fn merged(map: &yaml_rust::yaml::Hash) -> Vec<&yaml_rust::yaml::Hash> {
+    match map.get(&Yaml::String(MERGE_KEY.to_owned())) {
+        Some(Yaml::Hash(source)) => vec![source],
+        Some(Yaml::Array(sources)) => sources.iter().filter_map(Yaml::as_hash).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Value of `key` in `map` or, unless `raw`, in the mappings it merges
+fn lookup<'a>(map: &'a yaml_rust::yaml::Hash, key: &Yaml, raw: bool) -> Option<&'a Yaml> {
+    match map.get(key) {
+        Some(value) => Some(value),
+        None if raw => None,
+        None => merged(map).into_iter().find_map(|source| lookup(source, key, false)),
+    }
+}
+
+/// Entries of `map`. Unless `raw`, entries inherited through a `<<` merge
+/// key take its place, without overriding the keys of `map`, and earlier
+/// mappings of a merged sequence take precedence over later ones.
+fn entries(map: &yaml_rust::yaml::Hash, raw: bool) -> Vec<(&Yaml, &Yaml)> {
+    let merge_key = Yaml::String(MERGE_KEY.to_owned());
+    if raw || !map.contains_key(&merge_key) {
+        return map.iter().collect();
+    }
+    let mut entries: Vec<(&Yaml, &Yaml)> = Vec::new();
+    for (key, value) in map.iter() {
+        if *key != merge_key {
+            entries.push((key, value));
+            continue;
+        }
+        for source in merged(map) {
+            for (key, value) in self::entries(source, false) {
+                if !map.contains_key(key) && !entries.iter().any(|(k, _)| *k == key) {
+                    entries.push((key, value));
+                }
+            }
+        }
+    }
+    entries
+}
+
+fn get<'a>(doc: &'a Yaml, path: &[Yaml], raw: bool) -> &'a Yaml {
+    let key = match path.first() {
+        Some(key) => key,
+        None => return doc,
+    };
+    let child = match (doc, key) {
+        (Yaml::Array(array), Yaml::Integer(i)) => select(&Subscript::Index(*i), array.len()).first().map(|&i| &array[i]),
+        (Yaml::Hash(map), key) => lookup(map, key, raw),
+        _ => None,
+    };
+    match child {
+        Some(child) => get(child, &path[1..], raw),
+        None => &Yaml::BadValue,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    fn load(s: &str) -> Yaml {
+        YamlLoader::load_from_str(s).unwrap().remove(0)
+    }
+
+    const SERVICES: &str = "
+services:
+    db:
+        image: postgres
+        scale: 1
+    front:
+        image: nginx
+        scale: 0
+    cache:
+        image: redis
+        scale: 2
+";
+
+    fn values<'m>(matches: &'m [Match<'_>]) -> Vec<&'m Yaml> {
+        matches.iter().map(|m| &*m.value).collect()
+    }
+
+    /// Paths of the nodes `query` matches in the document `yaml`
+    fn paths(query: &str, yaml: &str) -> Vec<String> {
+        let doc = load(yaml);
+        evaluate(&parse_query(query).unwrap(), &doc).iter().map(|m| m.path_string()).collect()
+    }
+
+    #[test]
+    fn test_evaluate_path() {
+        let doc = load(SERVICES);
+        let query = parse_query("services.db.image").unwrap();
+        let matches = evaluate(&query, &doc);
+        assert_eq!(matches, vec![Match {
+            document: 0,
+            path: vec![
+                PathSegment::Key(Yaml::String("services".to_owned())),
+                PathSegment::Key(Yaml::String("db".to_owned())),
+                PathSegment::Key(Yaml::String("image".to_owned())),
+            ],
+            value: Cow::Borrowed(&Yaml::String("postgres".to_owned())),
+            anchor: None,
+        }]);
+
+        let query = parse_query("services.missing.image").unwrap();
+        assert_eq!(evaluate(&query, &doc), vec![]);
+    }
+
+    #[test]
+    fn test_evaluate_wildcard() {
+        let doc = load(SERVICES);
+        let query = parse_query("services.*.image").unwrap();
+        assert_eq!(values(&evaluate(&query, &doc)), vec![
+            &Yaml::String("postgres".to_owned()),
+            &Yaml::String("nginx".to_owned()),
+            &Yaml::String("redis".to_owned()),
+        ]);
+
+        let doc = load("list: [a, b, c]");
+        let query = parse_query("list.1").unwrap();
+        let matches = evaluate(&query, &doc);
+        assert_eq!(values(&matches), vec![&Yaml::String("b".to_owned())]);
+        assert_eq!(matches[0].path, vec![
+            PathSegment::Key(Yaml::String("list".to_owned())),
+            PathSegment::Index(1),
+        ]);
+        assert_eq!(matches[0].path_string(), "list.1");
+        let query = parse_query("list.3").unwrap();
+        assert_eq!(evaluate(&query, &doc), vec![]);
+    }
+
+    #[test]
+    fn test_evaluate_condition() {
+        let doc = load(SERVICES);
+        let query = parse_query("services.*(scale >= 1).image").unwrap();
+        assert_eq!(values(&evaluate(&query, &doc)), vec![
+            &Yaml::String("postgres".to_owned()),
+            &Yaml::String("redis".to_owned()),
+        ]);
+
+        let query = parse_query("services.*(image == 'nginx' || scale > 1).image").unwrap();
+        assert_eq!(values(&evaluate(&query, &doc)), vec![
+            &Yaml::String("nginx".to_owned()),
+            &Yaml::String("redis".to_owned()),
+        ]);
+
+        let query = parse_query("services.*(scale != 0 && (image < 'q' ^ scale > 1)).image").unwrap();
+        assert_eq!(values(&evaluate(&query, &doc)), vec![
+            &Yaml::String("postgres".to_owned()),
+            &Yaml::String("redis".to_owned()),
+        ]);
+    }
+
+    #[test]
+    fn test_evaluate_precedence() {
+        let doc = load("flag: true");
+        let check = |condition: &str| {
+            let query = parse_query(&format!("({})", condition)).unwrap();
+            Evaluator::new().check(Scope { node: &doc, ancestors: &[] }, &query.path[0].condition).unwrap()
+        };
+        assert!(check("true || false && false"));
+        assert!(check("false && true ^ true"));
+        assert!(check("true ^ true || true"));
+        assert!(check("true ^ false && false"));
+        assert!(!check("!false && false"));
+        assert!(!check("!(false || true)"));
+        assert!(check("!!flag"));
+        assert!(check("!flag || !missing"));
+    }
+
+    #[test]
+    fn test_evaluate_comparisons() {
+        let doc = load("
+int: 10
+real: 2.5
+undefined: .nan
+text: abc
+yes: true
+nothing: null
+list: [1, 2]
+");
+        let check = |condition: &str| {
+            let query = parse_query(&format!("({})", condition)).unwrap();
+            Evaluator::new().check(Scope { node: &doc, ancestors: &[] }, &query.path[0].condition).unwrap()
+        };
+        assert!(check("int == 10 && int >= 10 && int <= 10 && int > 9 && int < 11"));
+        assert!(check("int == 10.0 && real > 2 && real < 3 && real >= 2.5 && 2.5 <= real"));
+        assert!(check("undefined != undefined && !(undefined == undefined)"));
+        assert!(check("!(undefined > 0) && !(undefined < 0)"));
+        assert!(check("text == 'abc' && text < 'abd' && text >= 'abc' && text != 'ab'"));
+        assert!(check("yes == true && yes > false && yes != false"));
+        assert!(check("nothing == null && missing == null && nothing != 0 && text != null"));
+        assert!(check("list == list && list != int"));
+        assert!(check("text != 10 && !(text == 10) && !(text > 10) && !(text < 10)"));
+        assert!(check("!(nothing >= 0) && !(list > list)"));
+    }
+
+    #[test]
+    fn test_evaluate_strict() {
+        let doc = load(SERVICES);
+        let evaluator = Evaluator::new().strict(true);
+        let query = parse_query("services.*(scale > 0 && image != null).image").unwrap();
+        assert_eq!(evaluator.evaluate(&query, &doc).unwrap().len(), 2);
+
+        let query = parse_query("services.*(image > 0).image").unwrap();
+        assert_eq!(evaluator.evaluate(&query, &doc), Err(EvalError::TypeMismatch {
+            left: "string",
+            sign: CompareSign::Gt,
+            right: "integer",
+        }));
+        assert_eq!(evaluate(&query, &doc), vec![]);
+    }
+
+    #[test]
+    fn test_evaluate_stream() {
+        let docs = YamlLoader::load_from_str("
+kind: Service
+name: web
+---
+kind: Deployment
+name: web
+---
+kind: Service
+name: db
+").unwrap();
+        let evaluator = Evaluator::new();
+        let documents = |query: &str| -> Vec<(usize, Yaml)> {
+            let query = parse_query(query).unwrap();
+            evaluator.evaluate_stream(&query, &docs).unwrap().into_iter().map(|m| (m.document, m.value.into_owned())).collect()
+        };
+        let web = Yaml::String("web".to_owned());
+        let db = Yaml::String("db".to_owned());
+        assert_eq!(documents("name"), vec![(0, web.clone()), (1, web.clone()), (2, db.clone())]);
+        assert_eq!(documents("#2.name"), vec![(2, db.clone())]);
+        assert_eq!(documents("#*.name"), vec![(0, web.clone()), (1, web.clone()), (2, db.clone())]);
+        assert_eq!(documents("#(kind == 'Service').name"), vec![(0, web.clone()), (2, db.clone())]);
+        assert_eq!(documents("#0(kind == 'Service').name"), vec![(0, web.clone())]);
+        assert_eq!(documents("#1(kind == 'Service').name"), vec![]);
+        assert_eq!(documents("#1").len(), 1);
+    }
+
+    #[test]
+    fn test_evaluate_recursive() {
+        let doc = load(SERVICES);
+        let query = parse_query("..image").unwrap();
+        assert_eq!(values(&evaluate(&query, &doc)), vec![
+            &Yaml::String("postgres".to_owned()),
+            &Yaml::String("nginx".to_owned()),
+            &Yaml::String("redis".to_owned()),
+        ]);
+        let query = parse_query("services..*(scale > 0).image").unwrap();
+        assert_eq!(values(&evaluate(&query, &doc)), vec![
+            &Yaml::String("postgres".to_owned()),
+            &Yaml::String("redis".to_owned()),
+        ]);
+
+        let yaml = "
+a:
+    a:
+        b: 1
+    list:
+        - b: 2
+        - [{b: 3}]
+";
+        assert_eq!(paths("..a..b", yaml), vec!["a.a.b", "a.list.0.b", "a.list.1.0.b"]);
+        let doc = load(yaml);
+        let query = parse_query("..*(b > 1).b").unwrap();
+        assert_eq!(values(&evaluate(&query, &doc)), vec![&Yaml::Integer(2), &Yaml::Integer(3)]);
+        let query = parse_query("a.list..b").unwrap();
+        assert_eq!(values(&evaluate(&query, &doc)), vec![&Yaml::Integer(2), &Yaml::Integer(3)]);
+
+        // Aliased nodes are copies, and an alias inside its own anchor is a bad value
+        let yaml = "
+base: &base {b: 1, nested: &nested {b: 2}}
+copy: *base
+self: &self [{b: 3}, *self]
+";
+        assert_eq!(paths("..b", yaml), vec!["base.b", "base.nested.b", "copy.b", "copy.nested.b", "self.0.b"]);
+    }
+
+    #[test]
+    fn test_evaluate_subscript() {
+        let yaml = "list: [a, b, c, d, e]";
+        let doc = load(yaml);
+        let select = |q: &str| -> Vec<String> {
+            let query = parse_query(q).unwrap();
+            evaluate(&query, &doc).iter().map(|m| m.value.as_str().unwrap().to_owned()).collect()
+        };
+        assert_eq!(select("list[0]"), vec!["a"]);
+        assert_eq!(select("list[-1]"), vec!["e"]);
+        assert_eq!(select("list[5]"), Vec::<String>::new());
+        assert_eq!(select("list[-6]"), Vec::<String>::new());
+        assert_eq!(select("list[0,2,-1,9]"), vec!["a", "c", "e"]);
+        assert_eq!(select("list[1:3]"), vec!["b", "c"]);
+        assert_eq!(select("list[:2]"), vec!["a", "b"]);
+        assert_eq!(select("list[-2:]"), vec!["d", "e"]);
+        assert_eq!(select("list[::2]"), vec!["a", "c", "e"]);
+        assert_eq!(select("list[::-1]"), vec!["e", "d", "c", "b", "a"]);
+        assert_eq!(select("list[3:0:-2]"), vec!["d", "b"]);
+        assert_eq!(select("list[-10:10]"), vec!["a", "b", "c", "d", "e"]);
+        assert_eq!(select("list[::0]"), Vec::<String>::new());
+        assert_eq!(select("[0]"), Vec::<String>::new());
+
+        assert_eq!(paths("list[-1]", yaml), vec!["list.4"]);
+
+        let doc = load("
+services:
+    - {name: db, ports: [5432]}
+    - {name: front, ports: [80, 443]}
+");
+        let query = parse_query("services[:](ports.-1 == 443).name").unwrap();
+        assert_eq!(values(&evaluate(&query, &doc)), vec![&Yaml::String("front".to_owned())]);
+        let query = parse_query("services[0](ports.1 == 443)").unwrap();
+        assert_eq!(evaluate(&query, &doc), vec![]);
+    }
+
+    #[test]
+    fn test_evaluate_patterns() {
+        let yaml = "
+svc-a: 1
+svc-b: 2
+svcxb: 3
+db1: 4
+db12: 5
+a.b: 6
+axb: 7
+\"*\": 8
+";
+        assert_eq!(paths("svc-*", yaml), vec!["svc-a", "svc-b"]);
+        assert_eq!(paths("svc?b", yaml), vec!["svc-b", "svcxb"]);
+        assert_eq!(paths("svc-[ac]", yaml), vec!["svc-a"]);
+        assert_eq!(paths("svc-[!ac]", yaml), vec!["svc-b"]);
+        assert_eq!(paths("db[0-9]", yaml), vec!["db1"]);
+        assert_eq!(paths("~/^db\\d+$/", yaml), vec!["db1", "db12"]);
+        assert_eq!(paths("~/b$/", yaml), vec!["svc-b", "svcxb", "a\\.b", "axb"]);
+        assert_eq!(paths("a\\.b", yaml), vec!["a\\.b"]);
+        assert_eq!(paths("\\*", yaml), vec!["\\*"]);
+        assert_eq!(paths("*", yaml).len(), 8);
+    }
+
+    #[test]
+    fn test_evaluate_string_operators() {
+        let yaml = "
+services:
+    db:
+        image: postgres:13
+        ports: [5432]
+        tier: data
+    front:
+        image: nginx:latest
+        ports: [80, 443]
+        tier: web
+    cache:
+        image: redis
+        ports: []
+        tier: data
+    custom:
+        image: 12
+        tier: [data]
+";
+        let doc = load(yaml);
+        assert_eq!(paths("services.*(image ^= 'postgres')", yaml), vec!["services.db"]);
+        assert_eq!(paths("services.*(image $= ':latest')", yaml), vec!["services.front"]);
+        assert_eq!(paths("services.*(image *= 'e')", yaml), vec!["services.db", "services.front", "services.cache"]);
+        assert_eq!(paths("services.*(image =~ '^(nginx|redis)')", yaml), vec!["services.front", "services.cache"]);
+        assert_eq!(paths("services.*(image !~ ':')", yaml), vec!["services.cache", "services.custom"]);
+        assert_eq!(paths("services.*(ports *= 443)", yaml), vec!["services.front"]);
+        assert_eq!(paths("services.*(tier in ['web', 'cache'])", yaml), vec!["services.front"]);
+        assert_eq!(paths("services.*(5432 in ports)", yaml), vec!["services.db"]);
+        assert_eq!(paths("services.*(tier in [])", yaml), Vec::<String>::new());
+        assert_eq!(paths("services.*(image =~ tier)", yaml), Vec::<String>::new());
+
+        let query = parse_query("services.*(tier in 'data')").unwrap();
+        assert_eq!(Evaluator::new().strict(true).evaluate(&query, &doc), Err(EvalError::TypeMismatch {
+            left: "string",
+            sign: CompareSign::In,
+            right: "string",
+        }));
+        let query = parse_query("services.*(image ^= 'x')").unwrap();
+        assert!(Evaluator::new().strict(true).evaluate(&query, &doc).is_err());
+    }
+
+    #[test]
+    fn test_evaluate_functions() {
+        let doc = load(SERVICES);
+        assert_eq!(paths("services.*(length(image) == 5)", SERVICES), vec!["services.front", "services.cache"]);
+        assert_eq!(paths("services(length(keys(db)) == 2)", SERVICES), vec!["services"]);
+        assert_eq!(paths("services.*(upper(image) ^= 'POST')", SERVICES), vec!["services.db"]);
+        assert_eq!(paths("services.*(type(scale) == 'integer' && !exists(ports))", SERVICES).len(), 3);
+        assert_eq!(paths("services.*(default(ports, 0) == 0 && count(ports) == 0)", SERVICES).len(), 3);
+        assert_eq!(paths("services.*(to_number(lower('2')) == scale)", SERVICES), vec!["services.cache"]);
+        assert_eq!(paths("services.*(exists(image))", SERVICES).len(), 3);
+        assert_eq!(paths("services.*(length(scale) > 0)", SERVICES), Vec::<String>::new());
+
+        let query = parse_query("services.*(nope(image))").unwrap();
+        assert_eq!(Evaluator::new().evaluate(&query, &doc), Err(EvalError::UnknownFunction("nope".to_owned())));
+        assert_eq!(evaluate(&query, &doc), vec![]);
+
+        let query = parse_query("services.*(length(scale) > 0)").unwrap();
+        assert_eq!(
+            Evaluator::new().strict(true).evaluate(&query, &doc),
+            Err(EvalError::InvalidArguments { function: "length".to_owned(), arguments: vec!["integer"] })
+        );
+
+        fn registry(args: &[Yaml]) -> Option<Yaml> {
+            match args {
+                [Yaml::String(image)] => Some(Yaml::Boolean(image == "redis" || image == "nginx")),
+                _ => None,
+            }
+        }
+        fn length(_: &[Yaml]) -> Option<Yaml> {
+            Some(Yaml::Integer(0))
+        }
+        let evaluator = Evaluator::new().function("official", registry).function("length", length);
+        let query = parse_query("services.*(official(image))").unwrap();
+        assert_eq!(values(&evaluator.evaluate(&query, &doc).unwrap()), vec![&doc["services"]["front"], &doc["services"]["cache"]]);
+        let query = parse_query("services.*(length(image) == 0)").unwrap();
+        assert_eq!(evaluator.evaluate(&query, &doc).unwrap().len(), 3);
+
+        // Functions can capture their environment
+        let allowed = "postgres".to_owned();
+        let evaluator = Evaluator::new().function("allowed", move |args: &[Yaml]| match args {
+            [Yaml::String(image)] => Some(Yaml::Boolean(*image == allowed)),
+            _ => None,
+        });
+        let query = parse_query("services.*(allowed(image))").unwrap();
+        assert_eq!(values(&evaluator.evaluate(&query, &doc).unwrap()), vec![&doc["services"]["db"]]);
+        assert_eq!(format!("{:?}", evaluator), "Evaluator { strict: false, raw: false, functions: [\"allowed\"] }");
+    }
+
+    #[test]
+    fn test_evaluate_bare_statements() {
+        let yaml = "
+services:
+    db:
+        scale: 0
+        ports: []
+    front:
+        scale: null
+        debug: false
+    cache:
+        debug: true
+";
+        assert_eq!(paths("services.*(scale)", yaml), vec!["services.db"]);
+        assert_eq!(paths("services.*(!scale)", yaml), vec!["services.front", "services.cache"]);
+        assert_eq!(paths("services.*(exists(scale))", yaml), vec!["services.db", "services.front"]);
+        assert_eq!(paths("services.*(missing(scale))", yaml), vec!["services.cache"]);
+        assert_eq!(paths("services.*(debug || ports)", yaml), vec!["services.db", "services.cache"]);
+        assert_eq!(paths("services.*(scale.value)", yaml), Vec::<String>::new());
+        assert_eq!(paths("services.*(true)", yaml).len(), 3);
+        assert_eq!(paths("services.*(0 && '')", yaml).len(), 3);
+        assert_eq!(paths("services.*(null)", yaml).len(), 0);
+    }
+
+    #[test]
+    fn test_evaluate_references() {
+        let yaml = "
+defaults:
+    scale: 1
+    image: nginx
+services:
+    db:
+        image: postgres
+        scale: 2
+        replicas: 2
+    front:
+        image: nginx
+        scale: 1
+        replicas: 3
+    cache:
+        image: redis
+        scale: 0
+        ports:
+            - 6379
+";
+        assert_eq!(paths("services.*(scale > $.defaults.scale)", yaml), vec!["services.db"]);
+        assert_eq!(paths("services.*(image == $.defaults.image)", yaml), vec!["services.front"]);
+        assert_eq!(paths("services.*(@.scale == replicas)", yaml), vec!["services.db"]);
+        assert_eq!(paths("services.*.image(@ == ^.image)", yaml).len(), 3);
+        assert_eq!(paths("services.*.image(@ == ^^.front.image)", yaml), vec!["services.front.image"]);
+        assert_eq!(paths("services.*.scale(@ < ..replicas)", yaml), vec!["services.front.scale"]);
+        assert_eq!(paths("services.*.ports.*(@ == ^^^^.services.cache.ports.0)", yaml), vec!["services.cache.ports.0"]);
+        assert_eq!(paths("services.*(exists(^^^))", yaml), Vec::<String>::new());
+        assert_eq!(paths("services(exists($.defaults) && length(^) == 2)", yaml), vec!["services"]);
+        assert_eq!(paths("defaults($ == ^)", yaml), vec!["defaults"]);
+    }
+
+    #[test]
+    fn test_evaluate_filter() {
+        let doc = load(SERVICES);
+        assert_eq!(paths("services.*.image == 'nginx'", SERVICES), vec!["services.front.image"]);
+        assert_eq!(paths("services.*.scale > 0", SERVICES), vec!["services.db.scale", "services.cache.scale"]);
+        assert_eq!(paths("services.*.image =~ '^(redis|nginx)$'", SERVICES), vec!["services.front.image", "services.cache.image"]);
+        assert_eq!(paths("services.*.scale == ^.scale", SERVICES).len(), 3);
+        assert_eq!(paths("services.*.image == 'mysql'", SERVICES), Vec::<String>::new());
+        assert_eq!(paths("services.*(scale > 0).image != 'redis'", SERVICES), vec!["services.db.image"]);
+
+        let query = parse_query("services.*.image > 1").unwrap();
+        assert_eq!(
+            Evaluator::new().strict(true).evaluate(&query, &doc).map(|m| m.len()),
+            Err(EvalError::TypeMismatch { left: "string", sign: CompareSign::Gt, right: "integer" })
+        );
+    }
+
+    #[test]
+    fn test_evaluate_projection() {
+        let doc = load("
+name: stack
+services:
+    db:
+        image: postgres
+        scale: 1
+        ports: [5432]
+    front:
+        image: nginx
+        scale: 0
+");
+        let query = parse_query("services.*(scale > 0){image, replicas: scale, port: ports.0, stack: $.name}").unwrap();
+        let matches = evaluate(&query, &doc);
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].path_string(), "services.db");
+        assert_eq!(*matches[0].value, load("{image: postgres, replicas: 1, port: 5432, stack: stack}"));
+
+        let query = parse_query("services.*{name: length(image), ports}").unwrap();
+        assert_eq!(values(&evaluate(&query, &doc)), vec![
+            &load("{name: 8, ports: [5432]}"),
+            &load("{name: 5, ports: null}"),
+        ]);
+
+        let query = parse_query("services.*.scale == 0 {value: @, service: ^.image}").unwrap();
+        assert_eq!(values(&evaluate(&query, &doc)), vec![&load("{value: 0, service: nginx}")]);
+    }
+
+    #[test]
+    fn test_evaluate_pipeline() {
+        let docs = YamlLoader::load_from_str("
+defaults:
+    scale: 1
+services:
+    db:
+        image: postgres
+        scale: 2
+    front:
+        image: nginx
+        scale: 0
+        sidecars:
+            - image: envoy
+    cache:
+        image: redis
+        scale: 1
+---
+services:
+    queue:
+        image: rabbitmq
+        scale: 1
+").unwrap();
+        let evaluator = Evaluator::new();
+        let located = |p: &str| -> Vec<String> {
+            let pipeline = parse_pipeline(p).unwrap();
+            evaluator.evaluate_pipeline(&pipeline, &docs).unwrap().iter()
+                .map(|m| format!("{}:{}", m.document, m.path_string()))
+                .collect()
+        };
+        assert_eq!(
+            located("services.* | select(scale > 0) | .image"),
+            vec!["0:services.db.image", "0:services.cache.image", "1:services.queue.image"]
+        );
+        assert_eq!(located("services.*.image"), located("services | .* | .image"));
+        assert_eq!(located("services.* | select(scale < $.defaults.scale) | ..image"), vec![
+            "0:services.front.image",
+            "0:services.front.sidecars.0.image",
+        ]);
+        assert_eq!(located("services | #1 | .*"), vec!["1:services.queue"]);
+        assert_eq!(located("services.* | .image == 'redis'"), vec!["0:services.cache.image"]);
+        assert_eq!(located("..services | ..image").len(), 5);
+
+        let pipeline = parse_pipeline("services.*{name: image, replicas: scale} | select(replicas == 1) | .name").unwrap();
+        let matches = evaluator.evaluate_pipeline(&pipeline, &docs).unwrap();
+        assert_eq!(values(&matches), vec![&Yaml::String("redis".to_owned()), &Yaml::String("rabbitmq".to_owned())]);
+        assert_eq!(matches[0].path_string(), "services.cache");
+    }
+
+    #[test]
+    fn test_evaluate_aggregates() {
+        let docs = YamlLoader::load_from_str("
+services:
+    db:
+        image: postgres
+        scale: 2
+    front:
+        image: nginx
+        scale: 1.5
+    cache:
+        image: redis
+        scale: 0
+    queue:
+        image: nginx
+    proxy:
+        image: nginx
+        scale: 4
+").unwrap();
+        let run = |evaluator: &Evaluator, p: &str| -> Result<Vec<(String, Yaml)>, EvalError> {
+            let pipeline = parse_pipeline(p).unwrap();
+            Ok(evaluator.evaluate_pipeline(&pipeline, &docs)?.into_iter()
+                .map(|m| (m.path_string(), m.value.into_owned()))
+                .collect())
+        };
+        let evaluator = Evaluator::new();
+        let value = |p: &str| -> Yaml {
+            let mut results = run(&evaluator, p).unwrap();
+            assert_eq!(results.len(), 1, "{}", p);
+            results.remove(0).1
+        };
+        assert_eq!(value("services.* | count()"), Yaml::Integer(5));
+        assert_eq!(value("services.*.scale | sum()"), Yaml::Real("7.5".to_owned()));
+        assert_eq!(value("services.*(image == 'nginx').scale | sum()"), Yaml::Real("5.5".to_owned()));
+        assert_eq!(value("services.*(image != 'nginx').scale | sum()"), Yaml::Integer(2));
+        assert_eq!(value("services.missing | sum()"), Yaml::Integer(0));
+        assert_eq!(value("services.*.scale | avg()"), Yaml::Real("1.875".to_owned()));
+        assert_eq!(value("services.*(image != 'nginx').scale | avg()"), Yaml::Real("1.0".to_owned()));
+        assert_eq!(run(&evaluator, "services.missing | avg()"), Ok(vec![]));
+        assert_eq!(run(&evaluator, "services.*.scale | max()"), Ok(vec![("services.proxy.scale".to_owned(), Yaml::Integer(4))]));
+        assert_eq!(run(&evaluator, "services.*.scale | min()"), Ok(vec![("services.cache.scale".to_owned(), Yaml::Integer(0))]));
+        assert_eq!(value("services.*.image | max()"), Yaml::String("redis".to_owned()));
+        assert_eq!(
+            run(&evaluator, "services.*.image | unique()").unwrap().into_iter().map(|(path, _)| path).collect::<Vec<_>>(),
+            vec!["services.db.image", "services.front.image", "services.cache.image"]
+        );
+        assert_eq!(value("services.*.image | unique() | count()"), Yaml::Integer(3));
+        assert_eq!(
+            run(&evaluator, "services.* | sort_by(scale) | .image").unwrap().into_iter().map(|(_, v)| v).collect::<Vec<_>>(),
+            ["nginx", "redis", "nginx", "postgres", "nginx"].iter().map(|s| Yaml::String((*s).to_owned())).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            value("services.* | group_by(image) | .nginx | count()"),
+            Yaml::Integer(1)
+        );
+        let groups = value("services.*{image, scale} | group_by(image)");
+        assert_eq!(groups, YamlLoader::load_from_str("
+postgres: [{image: postgres, scale: 2}]
+nginx:
+    - {image: nginx, scale: 1.5}
+    - {image: nginx, scale: null}
+    - {image: nginx, scale: 4}
+redis: [{image: redis, scale: 0}]
+").unwrap().remove(0));
+
+        let strict = Evaluator::new().strict(true);
+        assert_eq!(
+            run(&strict, "services.*.image | sum()"),
+            Err(EvalError::InvalidArguments { function: "sum".to_owned(), arguments: vec!["string"] })
+        );
+        assert_eq!(
+            run(&strict, "services.*.* | max()"),
+            Err(EvalError::TypeMismatch { left: "integer", sign: CompareSign::Gt, right: "string" })
+        );
+        assert_eq!(run(&evaluator, "services.*.* | max()").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_evaluate_stream_aggregates() {
+        let docs = YamlLoader::load_from_str("
+kind: Deployment
+items: [1, 2, 3]
+image: nginx
+---
+kind: Deployment
+items: [4, 0.5, 1.5]
+image: nginx
+---
+kind: Service
+").unwrap();
+        let run = |p: &str| -> Vec<(usize, Yaml)> {
+            let pipeline = parse_pipeline(p).unwrap();
+            Evaluator::new().evaluate_pipeline(&pipeline, &docs).unwrap().into_iter()
+                .map(|m| (m.document, m.value.into_owned()))
+                .collect()
+        };
+        // Aggregates run over the matches of the whole stream
+        assert_eq!(run("items.* | count()"), vec![(0, Yaml::Integer(6))]);
+        assert_eq!(run("items.* | sum()"), vec![(0, Yaml::Real("12.0".to_owned()))]);
+        assert_eq!(run("items.* | max()"), vec![(1, Yaml::Integer(4))]);
+        assert_eq!(run("image | unique()"), vec![(0, Yaml::String("nginx".to_owned()))]);
+        assert_eq!(run("#(kind == 'Deployment') | count()"), vec![(0, Yaml::Integer(2))]);
+        assert_eq!(run("#2.items.* | count()"), vec![(0, Yaml::Integer(0))]);
+        assert_eq!(run("#1.items.* | count()"), vec![(1, Yaml::Integer(3))]);
+
+        // Grouping by document keeps them apart
+        assert_eq!(run("items.* | group_by(#)"), vec![(0, load("{0: [1, 2, 3], 1: [4, 0.5, 1.5]}"))]);
+        assert_eq!(run("items.* | group_by(#) | .1 | .* | count()"), vec![(0, Yaml::Integer(3))]);
+    }
+
+    #[test]
+    fn test_evaluate_merge_keys() {
+        let yaml = "
+base: &base
+    image: nginx
+    scale: 1
+    labels: {tier: web}
+services:
+    web:
+        <<: *base
+        scale: 3
+    api:
+        <<: [{scale: 5, debug: true}, *base]
+        port: 80
+    nested:
+        <<: {<<: *base, image: redis}
+";
+        assert_eq!(paths("services.web.*", yaml), vec!["services.web.image", "services.web.labels", "services.web.scale"]);
+        assert_eq!(paths("services.api.*", yaml), vec![
+            "services.api.scale",
+            "services.api.debug",
+            "services.api.image",
+            "services.api.labels",
+            "services.api.port",
+        ]);
+        assert_eq!(paths("services.*(image == 'nginx' && scale > 2)", yaml), vec!["services.web", "services.api"]);
+        assert_eq!(paths("services.*(labels.tier == 'web' && scale == ^.web.scale)", yaml), vec!["services.web"]);
+        assert_eq!(paths("services.nested.image == 'redis'", yaml), vec!["services.nested.image"]);
+        assert_eq!(paths("services.nested.scale == 1", yaml), vec!["services.nested.scale"]);
+        assert_eq!(paths("services..tier", yaml).len(), 3);
+
+        let doc = load(yaml);
+        let raw = Evaluator::new().raw(true);
+        let query = parse_query("services.web.*").unwrap();
+        assert_eq!(values(&raw.evaluate(&query, &doc).unwrap()), vec![&doc["base"], &Yaml::Integer(3)]);
+        let query = parse_query("services.*(image == 'nginx')").unwrap();
+        assert_eq!(raw.evaluate(&query, &doc), Ok(vec![]));
+        let query = parse_query("services.web.\\<\\<.image").unwrap();
+        assert_eq!(values(&raw.evaluate(&query, &doc).unwrap()), vec![&Yaml::String("nginx".to_owned())]);
+    }
+
+    #[test]
+    fn test_evaluate_quoted_condition_keys() {
+        let yaml = "
+items:
+    - metadata:
+        labels: {app.kubernetes.io/name: web, app: x}
+    - metadata:
+        labels: {app.kubernetes.io/name: db}
+";
+        assert_eq!(paths("items.*(metadata.labels.'app.kubernetes.io/name' == 'web')", yaml), vec!["items.0"]);
+        assert_eq!(paths("items.*(@.metadata.labels.\"app.kubernetes.io/name\" == 'db')", yaml), vec!["items.1"]);
+        assert_eq!(paths("items.*('metadata'.labels.app == 'x')", yaml), vec!["items.0"]);
+        assert_eq!(paths("items.*('web' == metadata.labels.'app.kubernetes.io/name')", yaml), vec!["items.0"]);
+        assert!(parse_query("items.*(metadata.labels.'app == 'web')").is_err());
+    }
+
+    #[test]
+    fn test_evaluate_typed_keys() {
+        let yaml = "
+responses:
+    200: ok
+    '200': quoted
+    404: {description: missing}
+    true: yes
+    ~: nothing
+    1.5: real
+    [1, 2]: pair
+    {a: 1}: map
+list: [a, b]
+";
+        assert_eq!(paths("responses.200", yaml), vec!["responses.200"]);
+        assert_eq!(paths("responses.'200'", yaml), vec!["responses.'200'"]);
+        assert_eq!(paths("responses.true", yaml), vec!["responses.true"]);
+        assert_eq!(paths("responses.null", yaml), vec!["responses.null"]);
+        assert_eq!(paths("responses.1.5", yaml), Vec::<String>::new());
+        assert_eq!(paths("responses.`1.5`", yaml), vec!["responses.`1.5`"]);
+        assert_eq!(paths("responses.`[1, 2]`", yaml), vec!["responses.`[1, 2]`"]);
+        assert_eq!(paths("responses.`{a: 1}`", yaml), vec!["responses.`{a: 1}`"]);
+        assert_eq!(paths("responses.*", yaml).len(), 8);
+        assert_eq!(paths("responses.2*", yaml).len(), 2);
+        assert_eq!(paths("responses(@.404.description == 'missing')", yaml).len(), 1);
+        assert_eq!(paths("responses(@.200 == 'ok')", yaml).len(), 1);
+        assert_eq!(paths("responses(@.'200' == 'quoted')", yaml).len(), 1);
+        assert_eq!(paths("responses(@.\\200 == 'quoted')", yaml).len(), 1);
+        assert_eq!(paths("responses(@.`[1, 2]` == 'pair')", yaml).len(), 1);
+        assert_eq!(paths("list(@.1 == 'b' && !exists(@.'1'))", yaml), vec!["list"]);
+        assert_eq!(paths("responses.~/^[0-9]+$/(@ $= 'ted')", yaml), vec!["responses.'200'"]);
+        assert_eq!(paths("list.1", yaml), vec!["list.1"]);
+        assert_eq!(paths("list.'1'", yaml), Vec::<String>::new());
+
+        // Printed paths read back as the same keys
+        for path in paths("responses.*", yaml) {
+            assert_eq!(paths(&path, yaml), vec![path.clone()]);
+        }
+    }
+
+    #[test]
+    fn test_evaluate_quoted_keys() {
+        let yaml = r#"
+labels:
+    app.kubernetes.io/name: web
+    my key (old): legacy
+    it's: apostrophe
+    say "hi": quotes
+    'both '' and "': mixed
+    a\b: backslash
+    "'lead": leading
+"#;
+        assert_eq!(paths("labels.'app.kubernetes.io/name'", yaml), vec!["labels.app\\.kubernetes\\.io/name"]);
+        assert_eq!(paths("labels.\"my key (old)\"", yaml), vec!["labels.my\\ key\\ \\(old\\)"]);
+        assert_eq!(paths("labels.\"it's\"", yaml), vec!["labels.it\\'s"]);
+        assert_eq!(paths("labels.'say \\\"hi\\\"'", yaml), vec!["labels.say\\ \\\"hi\\\""]);
+
+        // Printed paths read back as the same keys
+        for path in paths("labels.*", yaml) {
+            assert_eq!(paths(&path, yaml), vec![path.clone()]);
+        }
+    }
+}
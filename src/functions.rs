@@ -0,0 +1,152 @@
+use super::evaluator::type_name;
+use yaml_rust::Yaml;
+use std::sync::Arc;
+
+/// A function callable in conditions. It receives the resolved arguments and
+/// returns `None` when it does not accept them.
+pub type Function = Arc<dyn Fn(&[Yaml]) -> Option<Yaml> + Send + Sync>;
+
+pub(crate) type Builtin = fn(&[Yaml]) -> Option<Yaml>;
+
+/// Looks up a built-in function
+pub(crate) fn builtin(name: &str) -> Option<Builtin> {
+    let function: Builtin = match name {
+        "length" => length,
+        "keys" => keys,
+        "type" => type_of,
+        "lower" => lower,
+        "upper" => upper,
+        "exists" => exists,
+        "missing" => missing,
+        "count" => count,
+        "to_number" => to_number,
+        "default" => default,
+        _ => return None,
+    };
+    Some(function)
+}
+
+/// Characters of a string or entries of a collection
+fn length(args: &[Yaml]) -> Option<Yaml> {
+    let len = match args {
+        [Yaml::String(s)] => s.chars().count(),
+        [Yaml::Array(array)] => array.len(),
+        [Yaml::Hash(map)] => map.len(),
+        _ => return None,
+    };
+    Some(Yaml::Integer(len as i64))
+}
+
+fn keys(args: &[Yaml]) -> Option<Yaml> {
+    match args {
+        [Yaml::Hash(map)] => Some(Yaml::Array(map.keys().cloned().collect())),
+        _ => None,
+    }
+}
+
+fn type_of(args: &[Yaml]) -> Option<Yaml> {
+    match args {
+        [value] => Some(Yaml::String(type_name(value).to_owned())),
+        _ => None,
+    }
+}
+
+fn lower(args: &[Yaml]) -> Option<Yaml> {
+    match args {
+        [Yaml::String(s)] => Some(Yaml::String(s.to_lowercase())),
+        _ => None,
+    }
+}
+
+fn upper(args: &[Yaml]) -> Option<Yaml> {
+    match args {
+        [Yaml::String(s)] => Some(Yaml::String(s.to_uppercase())),
+        _ => None,
+    }
+}
+
+fn exists(args: &[Yaml]) -> Option<Yaml> {
+    match args {
+        [value] => Some(Yaml::Boolean(*value != Yaml::BadValue)),
+        _ => None,
+    }
+}
+
+fn missing(args: &[Yaml]) -> Option<Yaml> {
+    match args {
+        [value] => Some(Yaml::Boolean(*value == Yaml::BadValue)),
+        _ => None,
+    }
+}
+
+/// Entries of a collection, one for other values and zero for missing ones
+fn count(args: &[Yaml]) -> Option<Yaml> {
+    let count = match args {
+        [Yaml::BadValue] => 0,
+        [Yaml::Array(array)] => array.len(),
+        [Yaml::Hash(map)] => map.len(),
+        [_] => 1,
+        _ => return None,
+    };
+    Some(Yaml::Integer(count as i64))
+}
+
+fn to_number(args: &[Yaml]) -> Option<Yaml> {
+    match args {
+        [Yaml::Integer(_)] | [Yaml::Real(_)] => Some(args[0].clone()),
+        [Yaml::String(s)] => {
+            let s = s.trim();
+            if let Ok(i) = s.parse::<i64>() {
+                Some(Yaml::Integer(i))
+            } else {
+                s.parse::<f64>().ok().map(|_| Yaml::Real(s.to_owned()))
+            }
+        },
+        _ => None,
+    }
+}
+
+/// The first argument unless it is missing or null
+fn default(args: &[Yaml]) -> Option<Yaml> {
+    match args {
+        [Yaml::BadValue, fallback] | [Yaml::Null, fallback] => Some(fallback.clone()),
+        [value, _] => Some(value.clone()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(name: &str, args: &[Yaml]) -> Option<Yaml> {
+        builtin(name).unwrap()(args)
+    }
+
+    fn string(s: &str) -> Yaml {
+        Yaml::String(s.to_owned())
+    }
+
+    #[test]
+    fn test_builtins() {
+        assert_eq!(call("length", &[string("héllo")]), Some(Yaml::Integer(5)));
+        assert_eq!(call("length", &[Yaml::Array(vec![Yaml::Null])]), Some(Yaml::Integer(1)));
+        assert_eq!(call("length", &[Yaml::Integer(1)]), None);
+        assert_eq!(call("type", &[Yaml::BadValue]), Some(string("missing value")));
+        assert_eq!(call("lower", &[string("DB")]), Some(string("db")));
+        assert_eq!(call("upper", &[string("db")]), Some(string("DB")));
+        assert_eq!(call("exists", &[Yaml::Null]), Some(Yaml::Boolean(true)));
+        assert_eq!(call("exists", &[Yaml::BadValue]), Some(Yaml::Boolean(false)));
+        assert_eq!(call("missing", &[Yaml::Null]), Some(Yaml::Boolean(false)));
+        assert_eq!(call("missing", &[Yaml::BadValue]), Some(Yaml::Boolean(true)));
+        assert_eq!(call("count", &[Yaml::BadValue]), Some(Yaml::Integer(0)));
+        assert_eq!(call("count", &[string("a")]), Some(Yaml::Integer(1)));
+        assert_eq!(call("to_number", &[string(" 42 ")]), Some(Yaml::Integer(42)));
+        assert_eq!(call("to_number", &[string("1.5")]), Some(Yaml::Real("1.5".to_owned())));
+        assert_eq!(call("to_number", &[string("x")]), None);
+        assert_eq!(call("default", &[Yaml::BadValue, Yaml::Integer(1)]), Some(Yaml::Integer(1)));
+        assert_eq!(call("default", &[Yaml::Integer(2), Yaml::Integer(1)]), Some(Yaml::Integer(2)));
+        assert_eq!(call("default", &[Yaml::Integer(2)]), None);
+        assert!(builtin("unknown").is_none());
+    }
+}
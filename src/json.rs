@@ -0,0 +1,115 @@
+use yaml_rust::Yaml;
+use std::fmt::Write;
+
+/// Serializes `value` as compact JSON.
+///
+/// Doubles are written as JSON numbers except infinities and NaN, which have
+/// no JSON form and become `null` like `Yaml::Null`, bad values and aliases.
+/// Mapping keys that are not strings are written as strings: scalars with
+/// their YAML text and collections with their JSON text.
+pub fn to_json(value: &Yaml) -> String {
+    let mut out = String::new();
+    write_value(&mut out, value);
+    out
+}
+
+fn write_value(out: &mut String, value: &Yaml) {
+    match value {
+        Yaml::Real(_) => match value.as_f64() {
+            Some(f) if f.is_finite() => write!(out, "{:?}", f).unwrap(),
+            _ => out.push_str("null"),
+        },
+        Yaml::Integer(i) => write!(out, "{}", i).unwrap(),
+        Yaml::String(s) => write_string(out, s),
+        Yaml::Boolean(b) => write!(out, "{}", b).unwrap(),
+        Yaml::Array(array) => {
+            out.push('[');
+            for (i, v) in array.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_value(out, v);
+            }
+            out.push(']');
+        },
+        Yaml::Hash(map) => {
+            out.push('{');
+            for (i, (k, v)) in map.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_string(out, &key_string(k));
+                out.push(':');
+                write_value(out, v);
+            }
+            out.push('}');
+        },
+        Yaml::Null | Yaml::Alias(_) | Yaml::BadValue => out.push_str("null"),
+    }
+}
+
+fn key_string(key: &Yaml) -> String {
+    match key {
+        Yaml::String(s) | Yaml::Real(s) => s.clone(),
+        Yaml::Integer(i) => i.to_string(),
+        Yaml::Boolean(b) => b.to_string(),
+        Yaml::Null | Yaml::Alias(_) | Yaml::BadValue => "null".to_owned(),
+        Yaml::Array(_) | Yaml::Hash(_) => to_json(key),
+    }
+}
+
+fn write_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\u{8}' => out.push_str("\\b"),
+            '\u{c}' => out.push_str("\\f"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    fn json(s: &str) -> String {
+        to_json(&YamlLoader::load_from_str(s).unwrap()[0])
+    }
+
+    #[test]
+    fn test_to_json_scalars() {
+        assert_eq!(json("10"), "10");
+        assert_eq!(json("1.5"), "1.5");
+        assert_eq!(json("1e3"), "1000.0");
+        assert_eq!(json(".inf"), "null");
+        assert_eq!(json(".nan"), "null");
+        assert_eq!(json("~"), "null");
+        assert_eq!(json("true"), "true");
+        assert_eq!(json("'say \"hi\"\\n\t'"), "\"say \\\"hi\\\"\\\\n\\t\"");
+        assert_eq!(to_json(&Yaml::Alias(1)), "null");
+        assert_eq!(to_json(&Yaml::BadValue), "null");
+    }
+
+    #[test]
+    fn test_to_json_collections() {
+        assert_eq!(json("[1, a, [], {}]"), "[1,\"a\",[],{}]");
+        assert_eq!(json("
+services:
+    db:
+        ports: [5432]
+"), "{\"services\":{\"db\":{\"ports\":[5432]}}}");
+        assert_eq!(
+            json("{200: ok, 1.5: real, true: yes, ~: none, [a, 1]: list}"),
+            "{\"200\":\"ok\",\"1.5\":\"real\",\"true\":\"yes\",\"null\":\"none\",\"[\\\"a\\\",1]\":\"list\"}"
+        );
+    }
+}
@@ -1,6 +1,14 @@
 // pub(self) mod parsers;
 mod parsers;
-pub use parsers::parse_query;
+mod evaluator;
+mod json;
+mod document;
+mod functions;
+pub use parsers::{parse_query, parse_pipeline};
+pub use evaluator::{evaluate, Evaluator};
+pub use json::to_json;
+pub use document::Document;
+pub use functions::Function;
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Relation {
@@ -25,7 +33,22 @@ pub enum Statement {
     String(String),
     Double(f64),
     None,
-    Path(Vec<String>),
+    /// Path from the candidate node, written bare or after `@`
+    Path(Vec<yaml_rust::Yaml>),
+    /// `$.a.b`, path from the document root
+    Root(Vec<yaml_rust::Yaml>),
+    /// `^.a` or `..a`, path from an ancestor of the candidate node. `^` is
+    /// its parent and every further `^` goes one level up.
+    Parent(usize, Vec<yaml_rust::Yaml>),
+    /// `[a, b, c]`, the right side of `in`
+    List(Vec<Statement>),
+    /// Regular expression on the right side of `=~` and `!~`, compiled when the query is parsed
+    Pattern(Pattern),
+    /// `name(args)`, a call to a function of the evaluator
+    Function {
+        name: String,
+        args: Vec<Statement>,
+    },
 }
 
 
@@ -37,6 +60,38 @@ pub enum CompareSign {
     Lt,
     Ge,
     Le,
+    /// `=~`, the left string matches the regular expression on the right
+    Match,
+    /// `!~`
+    NotMatch,
+    /// `^=`
+    StartsWith,
+    /// `$=`
+    EndsWith,
+    /// `*=`, substring of a string or element of a sequence
+    Contains,
+    /// `in`, element of the sequence on the right
+    In,
+}
+
+impl std::fmt::Display for CompareSign {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let sign = match self {
+            CompareSign::Eq => "==",
+            CompareSign::Ne => "!=",
+            CompareSign::Gt => ">",
+            CompareSign::Lt => "<",
+            CompareSign::Ge => ">=",
+            CompareSign::Le => "<=",
+            CompareSign::Match => "=~",
+            CompareSign::NotMatch => "!~",
+            CompareSign::StartsWith => "^=",
+            CompareSign::EndsWith => "$=",
+            CompareSign::Contains => "*=",
+            CompareSign::In => "in",
+        };
+        write!(f, "{}", sign)
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -46,23 +101,248 @@ pub struct Condition {
     pub right: Statement,
 }
 
+/// Bracketed array subscript of a path entry, negative indices count from the end
+#[derive(Clone, Debug, PartialEq)]
+pub enum Subscript {
+    /// `[i]`
+    Index(i64),
+    /// `[i,j,k]`
+    List(Vec<i64>),
+    /// `[start:end:step]`, with the bounds and step of Python slices
+    Slice {
+        start: Option<i64>,
+        end: Option<i64>,
+        step: Option<i64>,
+    },
+}
+
+/// Compiled key pattern of a path entry, built once when the query is parsed
+#[derive(Clone, Debug)]
+pub struct Pattern(regex::Regex);
+
+impl Pattern {
+    pub fn new(re: &str) -> Result<Pattern, regex::Error> {
+        regex::Regex::new(re).map(Pattern)
+    }
+
+    pub fn is_match(&self, key: &str) -> bool {
+        self.0.is_match(key)
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct PathEntry {
+    /// Key as written, without escapes for literal keys
     pub key: Option<String>,
+    /// Set for glob and `~/regex/` keys, which are matched against the text
+    /// of scalar keys
+    pub pattern: Option<Pattern>,
+    /// Mapping key matched by equality: the YAML scalar a plain key reads
+    /// as, the string of a quoted key or the value of a `` `...` `` key.
+    /// Unset for wildcards and patterns.
+    pub literal: Option<yaml_rust::Yaml>,
+    /// Selects elements of sequences instead of `key`
+    pub subscript: Option<Subscript>,
     pub condition: Option<Vec<ConditionListItem>>,
+    /// Set for `..` segments, which match the key at any depth
+    pub recursive: bool,
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Query {
+    /// Selects documents of a multi-document stream
+    pub document: Option<PathEntry>,
     pub path: Vec<PathEntry>,
+    /// Trailing `sign value` comparison each matched value must satisfy,
+    /// with the matched value itself as the left operand
+    pub filter: Option<Condition>,
+    /// Trailing `{...}` projection building a mapping from each matched value
+    pub projection: Option<Vec<Field>>,
 }
 
+/// Entry of a projection, `name: value` or a path named after its last key
+#[derive(Clone, Debug, PartialEq)]
+pub struct Field {
+    pub name: String,
+    /// Resolved relative to the matched value
+    pub value: Statement,
+}
+
+/// Step of a pipeline, applied to every match of the previous step
+#[derive(Clone, Debug, PartialEq)]
+pub enum Stage {
+    /// Query relative to the match, written with a leading `.`
+    Query(Box<Query>),
+    /// `select(condition)`, keeps the matches satisfying the condition
+    Select(Vec<ConditionListItem>),
+    /// Function over the whole match set such as `sum()`
+    Aggregate(Aggregate),
+}
+
+/// Pipeline stage computed over all matches of the previous stage
+#[derive(Clone, Debug, PartialEq)]
+pub enum Aggregate {
+    /// `count()`, number of matches
+    Count,
+    /// `sum()` of the numeric values
+    Sum,
+    /// `min()`, the match with the smallest value
+    Min,
+    /// `max()`, the match with the largest value
+    Max,
+    /// `avg()`, mean of the numeric values
+    Avg,
+    /// `unique()`, the first match of each distinct value
+    Unique,
+    /// `sort_by(value)`, matches ordered by a value resolved relative to each of them
+    SortBy(Statement),
+    /// `group_by(value)`, mapping from a value resolved relative to each match
+    /// to the sequence of the matched values sharing it
+    GroupBy(Statement),
+    /// `group_by(#)`, mapping from the index of the document of each match
+    /// to the sequence of the matched values in it
+    GroupByDocument,
+}
+
+impl std::fmt::Display for Aggregate {
+    /// Writes the function name
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", match self {
+            Aggregate::Count => "count",
+            Aggregate::Sum => "sum",
+            Aggregate::Min => "min",
+            Aggregate::Max => "max",
+            Aggregate::Avg => "avg",
+            Aggregate::Unique => "unique",
+            Aggregate::SortBy(_) => "sort_by",
+            Aggregate::GroupBy(_) | Aggregate::GroupByDocument => "group_by",
+        })
+    }
+}
+
+/// `query | stage | ...`, the first query runs on the documents
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pipeline {
+    pub stages: Vec<Stage>,
+}
 
-#[derive(Default)]
-pub struct ParseError;
+
+/// Step from a node to one of its children
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PathSegment {
+    /// Key of a mapping entry
+    Key(yaml_rust::Yaml),
+    /// Index of a sequence element
+    Index(usize),
+}
+
+impl std::fmt::Display for PathSegment {
+    /// Writes the segment in query syntax
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use yaml_rust::Yaml;
+        match self {
+            // Strings that would read as another scalar type are quoted
+            PathSegment::Key(Yaml::String(s)) if !matches!(Yaml::from_str(s), Yaml::String(_)) || s.is_empty() => {
+                let quote = if s.contains('\'') { '"' } else { '\'' };
+                write!(f, "{}", quote)?;
+                for c in s.chars() {
+                    if c == quote || c == '\\' {
+                        write!(f, "\\")?;
+                    }
+                    write!(f, "{}", c)?;
+                }
+                write!(f, "{}", quote)
+            },
+            PathSegment::Key(Yaml::String(s)) => {
+                for c in s.chars() {
+                    if "\\. \t()[{#*?~=<>!&|^'\"`".contains(c) {
+                        write!(f, "\\")?;
+                    }
+                    write!(f, "{}", c)?;
+                }
+                Ok(())
+            },
+            PathSegment::Key(Yaml::Integer(i)) => write!(f, "{}", i),
+            PathSegment::Key(Yaml::Real(s)) => write!(f, "`{}`", s),
+            PathSegment::Key(Yaml::Boolean(b)) => write!(f, "{}", b),
+            PathSegment::Key(Yaml::Null) => write!(f, "null"),
+            PathSegment::Key(key) => {
+                write!(f, "`{}`", document::flow_text(key, yaml_rust::scanner::TScalarStyle::Any))
+            },
+            PathSegment::Index(i) => write!(f, "{}", i),
+        }
+    }
+}
+
+/// A node selected by a query together with the path leading to it
+#[derive(Clone, Debug, PartialEq)]
+pub struct Match<'a> {
+    /// Index of the document in the stream
+    pub document: usize,
+    pub path: Vec<PathSegment>,
+    /// The matched node, or the mapping built for it by a projection
+    pub value: std::borrow::Cow<'a, yaml_rust::Yaml>,
+    /// Anchor the value was copied from through an alias or inherited from
+    /// through a `<<` merge key. Only known for matches of a `Document`.
+    pub anchor: Option<String>,
+}
+
+impl<'a> Match<'a> {
+    /// Path of the node in query syntax
+    pub fn path_string(&self) -> String {
+        self.path.iter().map(|segment| segment.to_string()).collect::<Vec<_>>().join(".")
+    }
+}
+
+/// Query syntax error
+#[derive(Clone, Default, PartialEq)]
+pub struct ParseError {
+    /// Byte offset of the failure in the query
+    pub offset: usize,
+    /// Tokens that would have been accepted at `offset`
+    pub expected: Vec<String>,
+    /// Unparsed input at `offset` up to the next whitespace
+    pub fragment: String,
+}
+impl ParseError {
+    fn message(&self) -> String {
+        let mut message = if self.fragment.is_empty() {
+            "unexpected end of query".to_owned()
+        } else {
+            format!("unexpected `{}`", self.fragment)
+        };
+        if let Some((last, rest)) = self.expected.split_last() {
+            message.push_str(", expected ");
+            if !rest.is_empty() {
+                message.push_str(&rest.join(", "));
+                message.push_str(" or ");
+            }
+            message.push_str(last);
+        }
+        message
+    }
+
+    /// Renders `query` with a caret under the failure point
+    pub fn diagnostic(&self, query: &str) -> String {
+        let column = query.get(..self.offset).map_or(0, |s| s.chars().count());
+        format!("{}\n{}^ {}", query, " ".repeat(column), self.message())
+    }
+}
 impl std::fmt::Display for ParseError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "A parsing error occurred.")
+        let mut message = self.message();
+        message[..1].make_ascii_uppercase();
+        write!(f, "{} at offset {}.", message, self.offset)
     }
 }
 impl std::fmt::Debug for ParseError {
@@ -77,3 +357,99 @@ impl From<ParseError> for std::io::Error {
         std::io::Error::new(std::io::ErrorKind::InvalidInput, pe)
     }
 }
+
+#[derive(Clone, PartialEq)]
+pub enum EvalError {
+    /// Two values of incompatible types were compared in strict mode
+    TypeMismatch {
+        left: &'static str,
+        sign: CompareSign,
+        right: &'static str,
+    },
+    /// The query calls a function the evaluator does not know
+    UnknownFunction(String),
+    /// A function did not accept its arguments in strict mode
+    InvalidArguments {
+        function: String,
+        arguments: Vec<&'static str>,
+    },
+}
+impl std::fmt::Display for EvalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EvalError::UnknownFunction(name) => write!(f, "Unknown function `{}`.", name),
+            EvalError::InvalidArguments { function, arguments } => write!(
+                f,
+                "Function `{}` cannot be called with ({}).",
+                function,
+                arguments.join(", ")
+            ),
+            EvalError::TypeMismatch { left, sign, right } => write!(
+                f,
+                "Cannot compare {} with {} using `{}`.",
+                left,
+                right,
+                sign
+            ),
+        }
+    }
+}
+impl std::fmt::Debug for EvalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        <EvalError as std::fmt::Display>::fmt(self, f)
+    }
+}
+impl std::error::Error for EvalError { }
+
+impl From<EvalError> for std::io::Error {
+    fn from(ee: EvalError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, ee)
+    }
+}
+
+/// A matched node that cannot be rewritten in the source text
+#[derive(Clone, PartialEq)]
+pub enum EditError {
+    /// The node is not written out in the source, e.g. it is reached through an alias
+    NotInSource {
+        document: usize,
+        path: String,
+    },
+    /// The node holds an anchor that an alias outside of it refers to
+    AliasedAnchor {
+        document: usize,
+        path: String,
+        anchor: String,
+    },
+}
+impl std::fmt::Display for EditError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EditError::NotInSource { document, path } => write!(
+                f,
+                "Cannot edit `{}` of document {}: the node is not written in the source.",
+                path,
+                document
+            ),
+            EditError::AliasedAnchor { document, path, anchor } => write!(
+                f,
+                "Cannot edit `{}` of document {}: the anchor `&{}` is still referred to by an alias.",
+                path,
+                document,
+                anchor
+            ),
+        }
+    }
+}
+impl std::fmt::Debug for EditError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        <EditError as std::fmt::Display>::fmt(self, f)
+    }
+}
+impl std::error::Error for EditError { }
+
+impl From<EditError> for std::io::Error {
+    fn from(ee: EditError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, ee)
+    }
+}
@@ -1,227 +1,391 @@
 use yaml_rust::{YamlLoader, YamlEmitter, Yaml};
 use clap::Clap;
-use regex::Regex;
 
-// use std::io::prelude::*;
-// use std::io::{self, BufRead, Read};
+use std::io::Read;
+use std::str::FromStr;
+
+/// What is printed for every match
+enum Output {
+    /// The matched values
+    Values,
+    /// Paths of the matched values
+    Paths,
+    /// `path = value` lines
+    Pairs,
+    /// A JSON array of the matched values
+    Json,
+    /// One JSON value per line
+    Jsonl,
+}
+
+impl FromStr for Output {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "values" => Ok(Output::Values),
+            "paths" => Ok(Output::Paths),
+            "pairs" => Ok(Output::Pairs),
+            "json" => Ok(Output::Json),
+            "jsonl" => Ok(Output::Jsonl),
+            _ => Err(format!("unknown output mode `{}`", s)),
+        }
+    }
+}
 
 /// Query path over yaml file
 #[derive(Clap)]
 #[clap(version = "1.0", author = "Pavlikov V.")]
 struct Opts {
-    /// Sets a custom config file. Could have been an Option<T> with no default too
+    // Sets a custom config file. Could have been an Option<T> with no default too
     // #[clap(short = "c", long = "condition")]
     // condition: Option<String>,
+    /// Query path, required unless a subcommand is given
+    path: Option<String>,
+    /// Input files, stdin is read when none are given or for `-`
+    files: Vec<String>,
+    /// Fail on comparisons between incompatible types instead of treating them as false
+    #[clap(long)]
+    strict: bool,
+    /// Output mode. JSON values carry no file or document, see `--with-source`.
+    #[clap(long, default_value = "values", possible_values = &["values", "paths", "pairs", "json", "jsonl"])]
+    output: Output,
+    /// Wrap JSON values as `{"file":..,"doc":..,"path":..,"value":..}`, which are null for aggregate results
+    #[clap(long)]
+    with_source: bool,
+    /// Only query the document with this index in multi-document streams
+    #[clap(long)]
+    doc: Option<usize>,
+    /// Exit with status 1 when nothing matches the query. Errors exit with status 2.
+    #[clap(long)]
+    exit_status: bool,
+    /// Query `<<` merge keys as plain keys instead of resolving them
+    #[clap(long)]
+    raw: bool,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Clap)]
+enum Command {
+    /// Replace every matched node with a YAML value
+    Set(Set),
+    /// Remove every matched node from its parent
+    Delete(Delete),
+}
+
+#[derive(Clap)]
+struct Set {
     /// Query path
-    #[clap(required = true)]
     path: String,
+    /// YAML value to assign
+    value: String,
+    /// Input files, stdin is read when none are given or for `-`
+    files: Vec<String>,
+    /// Rewrite the input files instead of printing the result
+    #[clap(long)]
+    in_place: bool,
 }
 
-fn main() -> Result<(), std::io::Error> {
-    let opts: Opts = Opts::parse();
-
-    let query = quyaml::parse_query(&opts.path)?;
-    
-    let s =
-"
-services:
-    db:
-        image: postgres
-        scale: 1
-    front:
-        image: nginx
-        scale: 0
-";
-    let docs = YamlLoader::load_from_str(s).unwrap();
-    let doc = &docs[0];
+#[derive(Clap)]
+struct Delete {
+    /// Query path
+    path: String,
+    /// Input files, stdin is read when none are given or for `-`
+    files: Vec<String>,
+    /// Rewrite the input files instead of printing the result
+    #[clap(long)]
+    in_place: bool,
+}
 
-    let splitted_path = opts.path.split("/");
-    let path: Vec<&str> = splitted_path.collect();
+fn main() {
+    let opts: Opts = Opts::parse();
+    let evaluator = quyaml::Evaluator::new().strict(opts.strict).raw(opts.raw);
 
-    find(&doc, path.as_slice(), &[]);
-    Ok(())
+    // Status 1 is left for `--exit-status`, errors exit with 2 as in grep
+    if let Err(e) = run(&evaluator, &opts) {
+        eprintln!("Error: {}", e);
+        std::process::exit(2);
+    }
 }
 
-fn find(doc: &Yaml, path: &[&str], sp: &[Yaml]) {
-    if path.len() == 0 {
-        println!("{:?} = {:?}", &sp, &doc);
-        // Dump the YAML object
-        let mut out_str = String::new();
-        {
-            let mut emitter = YamlEmitter::new(&mut out_str);
-            emitter.dump(doc).unwrap(); // dump the YAML object to a String
-        }
-        println!("{}", out_str);
-    } else {
-        let key = path[0];
-        let re_condition = Regex::new(r"^(.*?)(?:\((.*?)\))?$").unwrap();
-        let cap = re_condition.captures(key).unwrap();
-        let mut key = cap.get(1).map_or("*", |m| m.as_str());
-        if key == "" {
-            key = "*";
-        }
-        let condition = &cap.get(2);
-
-        match &doc {
-            // Yaml::Hash(ref _map) if !key.contains("*") => {
-            //     find(&doc[key], &path[1..], &[sp, &[Yaml::String(key.to_owned())]].concat())
-            // },
-            Yaml::Array(ref _array) if key != "*" => {
-                if let Ok(intkey) = key.parse::<usize>() {
-                    if !check(&doc[intkey], &condition) {
-                        return;
-                    }
-                    find(&doc[intkey], &path[1..], &[sp, &[Yaml::Integer(intkey as i64)]].concat())
-                }
-            },
-
-            // Yaml::Hash(ref map) if key == "*" => {
-            //     for entry in map.iter() {
-            //         find(&entry.1, &path[1..], &[sp, &[entry.0.clone()]].concat());
-            //     }
-            // },
-
-            Yaml::Hash(ref map) => {
-                let re_str = key.replace("*", ".*?");
-                let re_str = format!("^{}$", re_str);
-                let re = Regex::new(&re_str).unwrap();
-
-                for entry in map.iter() {
-                    if let Yaml::String(s) = entry.0 {
-                        if re.is_match(s) {
-                            if !check(&entry.1, &condition) {
-                                continue;
-                            }
-                            find(&entry.1, &path[1..], &[sp, &[entry.0.clone()]].concat());
-                        }
-                    }
-                }
-            },
+fn run(evaluator: &quyaml::Evaluator, opts: &Opts) -> Result<(), std::io::Error> {
+    match (&opts.command, &opts.path) {
+        (Some(Command::Set(set)), _) => run_set(evaluator, set),
+        (Some(Command::Delete(delete)), _) => run_delete(evaluator, delete),
+        (None, Some(path)) => run_query(evaluator, opts, path),
+        (None, None) => {
+            eprintln!("error: a query path is required\n\nFor more information try --help");
+            std::process::exit(2);
+        },
+    }
+}
 
-            Yaml::Array(ref array) => {
-                for (i, v) in array.iter().enumerate() {
-                    if !check(&v, &condition) {
-                        continue;
-                    }
-                    find(&v, &path[1..], &[sp, &[Yaml::Integer(i as i64)]].concat());
-                }
-            },
-            _ => {
+fn run_query(evaluator: &quyaml::Evaluator, opts: &Opts, path: &str) -> Result<(), std::io::Error> {
+    let pipeline = parse_pipeline(path);
+    // Aggregates run once over the matches of all files, the stages before
+    // the first one run on every file separately
+    let split = pipeline.stages.iter()
+        .position(|stage| matches!(stage, quyaml::Stage::Aggregate(_)))
+        .unwrap_or(pipeline.stages.len());
+    let (per_file, joined) = pipeline.stages.split_at(split);
+    let per_file = quyaml::Pipeline { stages: per_file.to_vec() };
 
+    let files = inputs(&opts.files);
+    let mut documents = Vec::with_capacity(files.len());
+    for file in files.iter() {
+        documents.push(load_document(file)?);
+    }
+    // File and document index in the file of every document of the joined stream
+    let mut origins = Vec::new();
+    let mut matches = Vec::new();
+    for (f, document) in documents.iter().enumerate() {
+        for mut m in document.evaluate(evaluator, &per_file)? {
+            if opts.doc.is_some() && opts.doc != Some(m.document) {
+                continue;
             }
+            m.document += origins.len();
+            matches.push(m);
         }
+        origins.extend((0..document.docs().len()).map(|d| (f, d)));
+    }
+    let stream: Vec<Yaml>;
+    if !joined.is_empty() {
+        stream = documents.iter().flat_map(|document| document.docs().iter().cloned()).collect();
+        matches = evaluator.run_stages(joined, &stream, matches)?;
     }
-}
 
-fn check(doc: &Yaml, condition: &Option<regex::Match>) -> bool {
-    if let Some(condition_match) = condition {
-        let condition_str = condition_match.as_str();
-        let re = Regex::new(r"^\s*(.*?)\s*(==|=|!=|>|<)\s*(.*?)$").unwrap();
-        let cap = re.captures(&condition_str).unwrap();
-
-        let l = cap.get(1).map_or("", |m| m.as_str());
-        let e = cap.get(2).map_or("", |m| m.as_str());
-        let r = cap.get(3).map_or("", |m| m.as_str());
-
-        let l = normalize(&doc, l);
-        let r = normalize(&doc, r);
-
-        match l {
-            Yaml::Integer(a) => {
-                if let Yaml::Integer(b) = r {
-                    match e {
-                        "=" | "==" => {
-                            return a == b
-                        },
-                        "!=" => {
-                            return a != b
-                        },
-                        ">" => {
-                            return a > b
-                        },
-                        "<" => {
-                            return a < b
-                        },
-                        _ => {
-                            return false;
-                        }
-                    }
+    let labeled = files.len() > 1;
+    let mut json = Vec::new();
+    for m in matches.iter() {
+        // JSON output is never labeled so that it stays parseable
+        match opts.output {
+            Output::Json | Output::Jsonl => {
+                let value = if opts.with_source {
+                    json_with_source(m, &origins, &files)
                 } else {
-                    return false;
-                }
-            },
-            Yaml::String(a) => {
-                if let Yaml::String(b) = r {
-                    match e {
-                        "=" | "==" => {
-                            return a == b
-                        },
-                        "!=" => {
-                            return a != b
-                        },
-                        _ => {
-                            return false;
-                        }
-                    }
+                    quyaml::to_json(&m.value)
+                };
+                if let Output::Json = opts.output {
+                    json.push(value);
                 } else {
-                    return false;
+                    println!("{}", value);
                 }
             },
             _ => {
-                return false;
+                let mut label = String::new();
+                if !built(m) {
+                    let (f, d) = origins[m.document];
+                    if labeled {
+                        label.push_str(&files[f]);
+                    }
+                    if documents[f].docs().len() > 1 {
+                        label.push_str(&format!("#{}", d));
+                    }
+                }
+                print_match(m, &opts.output, &label);
             },
         }
     }
-    return true;
+    if let Output::Json = opts.output {
+        println!("[{}]", json.join(","));
+    }
+    if opts.exit_status && matches.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
 }
 
-fn normalize(doc: &Yaml, str: &str) -> Yaml {
-    match str.chars().next() {
-        Some('"') => {
-            let mut s = str.chars().next().map(|c| &str[c.len_utf8()..]).unwrap().to_string();
-            s.pop();
-            Yaml::String(s.to_owned())
-        },
-        Some('-') |
-        Some('0') |
-        Some('1') |
-        Some('2') |
-        Some('3') |
-        Some('4') |
-        Some('5') |
-        Some('6') |
-        Some('7') |
-        Some('8') |
-        Some('9') => {
-            Yaml::Integer(str.parse::<i64>().unwrap())
+/// Whether `m` is a value built by an aggregate such as `count()`, which
+/// belongs to no file, document or path
+fn built(m: &quyaml::Match) -> bool {
+    m.path.is_empty() && matches!(m.value, std::borrow::Cow::Owned(_))
+}
+
+/// JSON object with the file, document index in the file and path of `m`
+/// next to its value
+fn json_with_source(m: &quyaml::Match, origins: &[(usize, usize)], files: &[String]) -> String {
+    let (file, doc, path) = if built(m) {
+        ("null".to_owned(), "null".to_owned(), "null".to_owned())
+    } else {
+        let (f, d) = origins[m.document];
+        (
+            quyaml::to_json(&Yaml::String(files[f].clone())),
+            d.to_string(),
+            quyaml::to_json(&Yaml::String(m.path_string())),
+        )
+    };
+    format!(r#"{{"file":{},"doc":{},"path":{},"value":{}}}"#, file, doc, path, quyaml::to_json(&m.value))
+}
+
+fn run_set(evaluator: &quyaml::Evaluator, set: &Set) -> Result<(), std::io::Error> {
+    let pipeline = parse_pipeline(&set.path);
+    let value = YamlLoader::load_from_str(&set.value).map_err(|e| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{}: {}", set.value, e))
+    })?.into_iter().next().unwrap_or(Yaml::Null);
+
+    for file in inputs(&set.files).iter() {
+        let mut document = load_document(file)?;
+        for (d, path) in targets(evaluator, &pipeline, &document)? {
+            document.set(d, &path, &value)?;
         }
-        _ => {
-            let path: Vec<&str> = str.split(".").collect();
-            let value = get(&doc, path.as_slice());
-            value.clone()
+        write_output(file, &document.to_string(), set.in_place)?;
+    }
+    Ok(())
+}
+
+fn run_delete(evaluator: &quyaml::Evaluator, delete: &Delete) -> Result<(), std::io::Error> {
+    let pipeline = parse_pipeline(&delete.path);
+
+    for file in inputs(&delete.files).iter() {
+        let mut document = load_document(file)?;
+        // Aliases come after their anchors, deleting from the end removes
+        // them before the nodes they refer to
+        for (d, path) in targets(evaluator, &pipeline, &document)?.into_iter().rev() {
+            document.delete(d, &path)?;
         }
+        write_output(file, &document.to_string(), delete.in_place)?;
     }
+    Ok(())
 }
 
-fn get<'a>(doc: &'a Yaml, path: &[&str]) -> &'a Yaml {
-    if path.len() == 0 {
-        &doc
+/// Parses the query pipeline or exits with a diagnostic
+fn parse_pipeline(path: &str) -> quyaml::Pipeline {
+    match quyaml::parse_pipeline(path) {
+        Ok(pipeline) => pipeline,
+        Err(e) => {
+            eprintln!("{}", e.diagnostic(path));
+            std::process::exit(2);
+        }
+    }
+}
+
+fn inputs(files: &[String]) -> Vec<String> {
+    if files.is_empty() {
+        vec!["-".to_owned()]
     } else {
-        let key = path[0];
-        match &doc {
-            Yaml::Array(ref _array) if key != "*" => {
-                if let Ok(intkey) = key.parse::<usize>() {
-                    get(&doc[intkey], &path[1..])
-                } else {
-                    &Yaml::BadValue
-                }
-            },
-            Yaml::Hash(ref _map) if !key.contains("*") => {
-                get(&doc[key], &path[1..])
-            },
-            _ => {
-                &Yaml::BadValue
+        files.to_vec()
+    }
+}
+
+/// Loads a file keeping its source text for edits
+fn load_document(file: &str) -> Result<quyaml::Document, std::io::Error> {
+    let s = read_input(file)?;
+    quyaml::Document::parse(&s).map_err(|e| {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{}: {}", file, e))
+    })
+}
+
+fn read_input(file: &str) -> Result<String, std::io::Error> {
+    let mut s = String::new();
+    if file == "-" {
+        std::io::stdin().read_to_string(&mut s)?;
+    } else {
+        s = std::fs::read_to_string(file).map_err(|e| {
+            std::io::Error::new(e.kind(), format!("{}: {}", file, e))
+        })?;
+    }
+    Ok(s)
+}
+
+fn write_output(file: &str, output: &str, in_place: bool) -> Result<(), std::io::Error> {
+    if !in_place {
+        print!("{}", output);
+        Ok(())
+    } else if file == "-" {
+        Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "stdin cannot be rewritten in place"))
+    } else {
+        std::fs::write(file, output).map_err(|e| {
+            std::io::Error::new(e.kind(), format!("{}: {}", file, e))
+        })
+    }
+}
+
+/// Document index and path of every node matched by `pipeline`. Values built
+/// by projections and aggregates are not part of the document and are
+/// skipped, as are nodes reached through aliases or inherited through `<<`
+/// merge keys, which are edited where they are written.
+fn targets(
+    evaluator: &quyaml::Evaluator,
+    pipeline: &quyaml::Pipeline,
+    document: &quyaml::Document,
+) -> Result<Vec<(usize, Vec<quyaml::PathSegment>)>, std::io::Error> {
+    Ok(evaluator.evaluate_pipeline(pipeline, document.docs())?
+        .into_iter()
+        .filter(|m| matches!(m.value, std::borrow::Cow::Borrowed(_)))
+        .filter(|m| document.contains(m.document, &m.path))
+        .map(|m| (m.document, m.path))
+        .collect())
+}
+
+fn print_match(m: &quyaml::Match, output: &Output, label: &str) {
+    if !label.is_empty() {
+        print!("{}: ", label);
+    }
+    let path = if built(m) { "<aggregate>".to_owned() } else { m.path_string() };
+    match output {
+        Output::Values => println!("{}", format_value(&m.value)),
+        Output::Paths => println!("{}", path),
+        Output::Pairs => {
+            let value = format_value(&m.value);
+            // Values copied from an anchor are marked with a comment
+            let origin = m.anchor.as_ref().map_or(String::new(), |anchor| format!("  # from &{}", anchor));
+            if value.contains('\n') {
+                println!("{} ={}\n  {}", path, origin, value.replace('\n', "\n  "));
+            } else {
+                println!("{} = {}{}", path, value, origin);
+            }
+        },
+        Output::Json | Output::Jsonl => unreachable!(),
+    }
+}
+
+/// Formats scalars as plain text and collections as YAML
+fn format_value(value: &Yaml) -> String {
+    match value {
+        Yaml::String(s) | Yaml::Real(s) => s.clone(),
+        Yaml::Integer(i) => i.to_string(),
+        Yaml::Boolean(b) => b.to_string(),
+        Yaml::Null => "null".to_owned(),
+        _ => {
+            // Dump the YAML object
+            let mut out_str = String::new();
+            {
+                let mut emitter = YamlEmitter::new(&mut out_str);
+                emitter.dump(value).unwrap(); // dump the YAML object to a String
             }
+            out_str.trim_start_matches("---\n").to_owned()
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MERGED: &str = "defaults: &defaults
+  image: nginx
+  scale: 1
+web:
+  <<: *defaults
+  scale: 3
+";
+
+    fn set(query: &str, value: &str) -> String {
+        let mut document = quyaml::Document::parse(MERGED).unwrap();
+        let pipeline = quyaml::parse_pipeline(query).unwrap();
+        let value = YamlLoader::load_from_str(value).unwrap().remove(0);
+        for (d, path) in targets(&quyaml::Evaluator::new(), &pipeline, &document).unwrap() {
+            document.set(d, &path, &value).unwrap();
+        }
+        document.to_string()
+    }
+
+    #[test]
+    fn test_set_merged() {
+        // Inherited keys are left to the merged mapping
+        assert_eq!(set("web.*", "5"), MERGED.replace("scale: 3", "scale: 5"));
+        assert_eq!(set("web.image", "redis"), MERGED);
+        assert_eq!(set("defaults.image", "redis"), MERGED.replace("nginx", "redis"));
+    }
+}
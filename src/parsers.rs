@@ -5,6 +5,67 @@ use nom::multi as multi;
 use nom::combinator as combinator;
 use nom::sequence as sequence;
 use nom::branch as branch;
+use yaml_rust::Yaml;
+
+/// Parser error tracking the position of a failure and the tokens accepted there
+#[derive(Clone, Debug, PartialEq)]
+struct QueryError<'a> {
+    input: &'a str,
+    expected: Vec<&'static str>,
+}
+
+impl<'a> nom::error::ParseError<&'a str> for QueryError<'a> {
+    fn from_error_kind(input: &'a str, _kind: nom::error::ErrorKind) -> Self {
+        QueryError {
+            input,
+            expected: Vec::new(),
+        }
+    }
+
+    fn append(_input: &'a str, _kind: nom::error::ErrorKind, other: Self) -> Self {
+        other
+    }
+
+    /// Keeps the error that got further, merging expectations of errors at the same position
+    fn or(mut self, other: Self) -> Self {
+        if self.input.len() < other.input.len() {
+            self
+        } else if self.input.len() > other.input.len() {
+            other
+        } else {
+            for token in other.expected {
+                if !self.expected.contains(&token) {
+                    self.expected.push(token);
+                }
+            }
+            self
+        }
+    }
+}
+
+type IResult<'a, O> = nom::IResult<&'a str, O, QueryError<'a>>;
+
+/// Key of a path entry as written, its pattern and the mapping key it stands for
+type Key = (String, Option<Pattern>, Option<Yaml>);
+
+const OPERAND_END: &[&str] = &["comparison operator", "`&&`", "`||`", "`^`", "`)`"];
+const QUERY_END: &[&str] = &["`.`", "`[`", "`(`", "comparison operator", "`{`", "end of query"];
+const PIPELINE_END: &[&str] = &["`.`", "`[`", "`(`", "comparison operator", "`{`", "`|`", "end of query"];
+
+/// Sets the tokens reported as expected when `parser` fails without consuming input
+fn expect<'a, O, F>(expected: &'static [&'static str], parser: F) -> impl Fn(&'a str) -> IResult<'a, O>
+where
+    F: Fn(&'a str) -> IResult<'a, O>,
+{
+    move |i: &'a str| {
+        parser(i).map_err(|e| e.map(|mut e| {
+            if e.input.len() >= i.trim_start().len() {
+                e.expected = expected.to_vec();
+            }
+            e
+        }))
+    }
+}
 
 fn trim<I, O2, E: nom::error::ParseError<I>, G>(sep: G) -> impl Fn(I) -> nom::IResult<I, O2, E>
 where
@@ -21,38 +82,64 @@ where
     }
 }
 
+/// Characters ending a key of a condition path unless escaped
+const PATH_END: &str = "\\. \t=<>!&|^()[]{},";
+
 #[allow(unused)]
-fn unescaped_path(i: &str) -> nom::IResult<&str, Vec<String>> {
-    trim(
-        multi::separated_nonempty_list(
-            character::char('.'),
-            bytes::escaped_transform(
-                bytes::is_not("\\. \t=<>!&|^()"),
-                '\\',
-                bytes::is_a("\\. \t()"),
-            )
-        )
+fn unescaped_path(i: &str) -> IResult<'_, Vec<Yaml>> {
+    trim(multi::separated_nonempty_list(character::char('.'), path_key))(i)
+}
+
+/// Key of a condition path, typed as in `key`: plain keys read as YAML
+/// scalars, escaped and quoted ones are strings and `` `...` `` ones are
+/// YAML flow values
+#[allow(unused)]
+fn path_key(i: &str) -> IResult<'_, Yaml> {
+    if i.starts_with(['"', '\'', '`']) {
+        let (rest, (_, _, literal)) = literal_key(i)?;
+        return Ok((rest, literal.unwrap_or(Yaml::BadValue)));
+    }
+    let (rest, name) = bytes::escaped_transform(bytes::is_not(PATH_END), '\\', bytes::take(1usize))(i)?;
+    let source = &i[..i.len() - rest.len()];
+    let key = if source == name { Yaml::from_str(&name) } else { Yaml::String(name) };
+    Ok((rest, key))
+}
+
+/// Path of at least two keys starting with a quoted one, which would
+/// otherwise read as a string
+#[allow(unused)]
+fn quoted_path(i: &str) -> IResult<'_, Vec<Yaml>> {
+    sequence::preceded(
+        combinator::peek(trim(character::one_of("\"'"))),
+        combinator::verify(unescaped_path, |path: &[Yaml]| path.len() > 1)
     )(i)
 }
 
+/// `"..."` or `'...'` string, a backslash escapes either quote or itself
 #[allow(unused)]
-fn quoted_string(i: &str) -> nom::IResult<&str, &str> {
+fn quoted_string(i: &str) -> IResult<'_, String> {
     branch::alt((
         sequence::delimited(
             bytes::tag("\""),
-            bytes::escaped(
-                bytes::is_not("\\\""),
-                '\\',
-                bytes::is_a("\\\""),
+            combinator::map(
+                combinator::opt(bytes::escaped_transform(
+                    bytes::is_not("\\\""),
+                    '\\',
+                    branch::alt((bytes::tag("\\"), bytes::tag("\""), bytes::tag("'"))),
+                )),
+                Option::unwrap_or_default
             ),
             bytes::tag("\"")
         ),
         sequence::delimited(
             bytes::tag("'"),
-            bytes::escaped(
-                bytes::is_not("\\'"),
-                '\\',
-                bytes::is_a("\\'"),
+            combinator::map(
+                combinator::opt(bytes::escaped_transform(
+                    bytes::is_not("\\'"),
+                    '\\',
+                    branch::alt((bytes::tag("\\"), bytes::tag("\""), bytes::tag("'"))),
+                )),
+                Option::unwrap_or_default
             ),
             bytes::tag("'")
         ),
@@ -60,7 +147,7 @@ fn quoted_string(i: &str) -> nom::IResult<&str, &str> {
 }
 
 #[allow(unused)]
-fn boolean(i: &str) -> nom::IResult<&str, bool> {
+fn boolean(i: &str) -> IResult<'_, bool> {
     branch::alt((
         combinator::map(bytes::tag("true"), |_| true),
         combinator::map(bytes::tag("false"), |_| false),
@@ -68,56 +155,141 @@ fn boolean(i: &str) -> nom::IResult<&str, bool> {
 }
 
 #[allow(unused)]
-fn value(i: &str) -> nom::IResult<&str, Statement> {
+fn value(i: &str) -> IResult<'_, Statement> {
     trim(
         branch::alt((
-            combinator::map(boolean, |v| Statement::Boolean(v)),
+            combinator::map(boolean, Statement::Boolean),
             combinator::map(bytes::tag("null"), |_| Statement::None),
             combinator::map(nom::number::complete::recognize_float, |s: &str| {
-                if s.chars().all(|c| c.is_numeric() || c == '-') {
-                    Statement::Integer(s.parse().unwrap())
-                } else {
-                    Statement::Double(s.parse().unwrap())
+                // Integers too large for i64 are compared as floats
+                match s.parse() {
+                    Ok(i) if s.chars().all(|c| c.is_numeric() || c == '-') => Statement::Integer(i),
+                    _ => Statement::Double(s.parse().unwrap()),
                 }
             }),
-            combinator::map(quoted_string, |s: &str| Statement::String(s.to_owned())),
-            combinator::map(unescaped_path, |path: Vec<String>| Statement::Path(path)),
+            combinator::map(quoted_path, Statement::Path),
+            combinator::map(quoted_string, Statement::String),
+            combinator::map(list, Statement::List),
+            function,
+            reference,
+            combinator::map(unescaped_path, Statement::Path),
         ))
     )(i)
 }
 
+/// Path following a `$`, `@` or `^` reference, empty for the referenced node itself
 #[allow(unused)]
-fn compare_sign(i: &str) -> nom::IResult<&str, CompareSign> {
+fn reference_path(i: &str) -> IResult<'_, Vec<Yaml>> {
+    branch::alt((
+        sequence::preceded(character::char('.'), unescaped_path),
+        combinator::map(combinator::not(bytes::is_not(PATH_END)), |_| Vec::new()),
+    ))(i)
+}
+
+/// `$` for the document root, `@` for the candidate node, `^` (repeated for
+/// each level) or `..` for its ancestors
+#[allow(unused)]
+fn reference(i: &str) -> IResult<'_, Statement> {
+    branch::alt((
+        combinator::map(sequence::preceded(character::char('$'), reference_path), Statement::Root),
+        combinator::map(sequence::preceded(character::char('@'), reference_path), Statement::Path),
+        combinator::map(
+            sequence::pair(multi::many1_count(character::char('^')), reference_path),
+            |(levels, path)| Statement::Parent(levels, path)
+        ),
+        combinator::map(
+            sequence::preceded(
+                bytes::tag(".."),
+                branch::alt((
+                    unescaped_path,
+                    combinator::map(combinator::not(bytes::is_not(PATH_END)), |_| Vec::new()),
+                ))
+            ),
+            |path| Statement::Parent(1, path)
+        ),
+    ))(i)
+}
+
+/// `name(value, ...)`
+#[allow(unused)]
+fn function(i: &str) -> IResult<'_, Statement> {
+    combinator::map(
+        sequence::pair(
+            sequence::terminated(
+                bytes::take_while1(|c: char| c.is_alphanumeric() || c == '_'),
+                character::char('(')
+            ),
+            combinator::cut(sequence::terminated(
+                multi::separated_list(character::char(','), value),
+                expect(&["`,`", "`)`"], trim(character::char(')')))
+            ))
+        ),
+        |(name, args): (&str, _)| Statement::Function { name: name.to_owned(), args }
+    )(i)
+}
+
+/// `[value, ...]`
+#[allow(unused)]
+fn list(i: &str) -> IResult<'_, Vec<Statement>> {
+    sequence::delimited(
+        character::char('['),
+        multi::separated_list(character::char(','), value),
+        expect(&["`,`", "`]`"], trim(character::char(']')))
+    )(i)
+}
+
+#[allow(unused)]
+fn compare_sign(i: &str) -> IResult<'_, CompareSign> {
     branch::alt((
         combinator::value(CompareSign::Eq, bytes::tag("==")),
         combinator::value(CompareSign::Ne, bytes::tag("!=")),
+        combinator::value(CompareSign::Match, bytes::tag("=~")),
+        combinator::value(CompareSign::NotMatch, bytes::tag("!~")),
+        combinator::value(CompareSign::StartsWith, bytes::tag("^=")),
+        combinator::value(CompareSign::EndsWith, bytes::tag("$=")),
+        combinator::value(CompareSign::Contains, bytes::tag("*=")),
         combinator::value(CompareSign::Ge, bytes::tag(">=")),
         combinator::value(CompareSign::Le, bytes::tag("<=")),
         combinator::value(CompareSign::Gt, bytes::tag(">")),
         combinator::value(CompareSign::Lt, bytes::tag("<")),
+        combinator::value(
+            CompareSign::In,
+            sequence::terminated(bytes::tag("in"), combinator::not(character::alphanumeric1))
+        ),
     ))(i)
 }
 
+/// `sign value`, string patterns of `=~` and `!~` are compiled here
 #[allow(unused)]
-fn condition(i: &str) -> nom::IResult<&str, Condition> {
-    trim(
-        combinator::map(
-            sequence::tuple((
-                value,
-                compare_sign,
-                value,
-            )),
-            |(left, relation, right)| Condition {
-                left: left,
-                sign: relation,
-                right: right,
+fn comparison(i: &str) -> IResult<'_, (CompareSign, Statement)> {
+    let (i, sign) = trim(compare_sign)(i)?;
+    let (rest, right) = combinator::cut(expect(&["value"], value))(i)?;
+    let right = match (&sign, right) {
+        (CompareSign::Match, Statement::String(re)) | (CompareSign::NotMatch, Statement::String(re)) => {
+            match Pattern::new(&re) {
+                Ok(pattern) => Statement::Pattern(pattern),
+                Err(_) => return Err(nom::Err::Failure(QueryError {
+                    input: i.trim_start(),
+                    expected: vec!["regular expression"],
+                })),
             }
-        )
+        },
+        (_, right) => right,
+    };
+    Ok((rest, (sign, right)))
+}
+
+/// `value sign value`
+#[allow(unused)]
+fn condition(i: &str) -> IResult<'_, Condition> {
+    combinator::map(
+        sequence::pair(value, comparison),
+        |(left, (sign, right))| Condition { left, sign, right }
     )(i)
 }
 
 #[allow(unused)]
-fn relation(i: &str) -> nom::IResult<&str, Relation> {
+fn relation(i: &str) -> IResult<'_, Relation> {
     trim(
         branch::alt((
             combinator::map(bytes::tag("||"), |_| Relation::Or),
@@ -128,44 +300,64 @@ fn relation(i: &str) -> nom::IResult<&str, Relation> {
 }
 
 #[allow(unused)]
-fn condition_list_item(i: &str) -> nom::IResult<&str, ConditionListItem> {
+fn condition_list_item(i: &str) -> IResult<'_, ConditionListItem> {
     trim(
         branch::alt((
             combinator::map(
-                sequence::delimited(
+                sequence::preceded(
                     bytes::tag("("),
-                    condition_list,
-                    bytes::tag(")")
+                    combinator::cut(
+                        sequence::terminated(
+                            condition_list,
+                            expect(OPERAND_END, bytes::tag(")"))
+                        )
+                    )
                 ),
-                |g| ConditionListItem::Group(g)
+                ConditionListItem::Group
             ),
-            combinator::map(condition, |st| ConditionListItem::Condition(st)),
-            combinator::map(value, |v| ConditionListItem::Statement(v)),
+            combinator::map(condition, ConditionListItem::Condition),
+            combinator::map(value, ConditionListItem::Statement),
         ))
     )(i)
 }
 
 #[allow(unused)]
-fn condition_list(i: &str) -> nom::IResult<&str, Vec<ConditionListItem>> {
-    let mut list = Vec::new();
+fn condition_operand(i: &str) -> IResult<'_, Vec<ConditionListItem>> {
+    combinator::map(
+        sequence::tuple((
+            multi::many0(
+                trim(combinator::value(ConditionListItem::Not, character::char('!')))
+            ),
+            condition_list_item
+        )),
+        |(mut acc, st)| {
+            acc.push(st);
+            acc
+        }
+    )(i)
+}
+
+#[allow(unused)]
+fn condition_list(i: &str) -> IResult<'_, Vec<ConditionListItem>> {
+    let list = Vec::new();
     combinator::map(
         sequence::tuple((
             multi::fold_many0(
                 sequence::tuple((
-                    condition_list_item,
-                    combinator::map(relation, |v| ConditionListItem::Relation(v))
+                    condition_operand,
+                    combinator::map(relation, ConditionListItem::Relation)
                 )),
                 list,
-                |mut acc: Vec<_>, (st, rel)| {
-                    acc.push(st);
+                |mut acc: Vec<_>, (operand, rel)| {
+                    acc.extend(operand);
                     acc.push(rel);
                     acc
                 }
             ),
-            condition_list_item
+            expect(&["condition"], condition_operand)
         )),
-        |(mut acc, st)| {
-            acc.push(st);
+        |(mut acc, operand)| {
+            acc.extend(operand);
             acc
         }
     )
@@ -173,60 +365,517 @@ fn condition_list(i: &str) -> nom::IResult<&str, Vec<ConditionListItem>> {
 }
 
 #[allow(unused)]
-fn query(i: &str) -> nom::IResult<&str, Query> {
+fn condition_group(i: &str) -> IResult<'_, Vec<ConditionListItem>> {
+    trim(
+        sequence::preceded(
+            bytes::tag("("),
+            combinator::cut(
+                sequence::terminated(
+                    condition_list,
+                    expect(OPERAND_END, bytes::tag(")")),
+                )
+            )
+        )
+    )(i)
+}
+
+/// Characters ending a key unless escaped
+const KEY_END: &str = ". \t=<>!&|^(){";
+
+/// Key of a path entry: a `~/regex/`, or a name in which `*`, `?` and
+/// `[...]` classes are glob wildcards. Any character can be escaped with
+/// `\`. A bracket that reads as a subscript ends the key.
+#[allow(unused)]
+fn key(i: &str) -> IResult<'_, Key> {
+    if i.starts_with("~/") {
+        return regex_key(i);
+    }
+    let mut name = String::new();
+    let mut glob = String::from("^");
+    let mut wildcards = false;
+    let mut rest = i;
+    while let Some(c) = rest.chars().next() {
+        let next = &rest[c.len_utf8()..];
+        match c {
+            '\\' => {
+                let escaped = match next.chars().next() {
+                    Some(escaped) => escaped,
+                    None => return Err(nom::Err::Failure(QueryError { input: next, expected: vec!["escaped character"] })),
+                };
+                name.push(escaped);
+                glob.push_str(&regex::escape(&escaped.to_string()));
+                rest = &next[escaped.len_utf8()..];
+                continue;
+            },
+            '*' => {
+                glob.push_str(".*");
+                wildcards = true;
+            },
+            '?' => {
+                glob.push('.');
+                wildcards = true;
+            },
+            '[' => {
+                match subscript(rest) {
+                    Ok(_) => break,
+                    Err(nom::Err::Error(_)) => {},
+                    Err(e) => return Err(e),
+                }
+                let close = match next.find(']') {
+                    Some(close) => close,
+                    None => return Err(nom::Err::Failure(QueryError { input: "", expected: vec!["`]`"] })),
+                };
+                glob.push_str(&glob_class(&next[..close]));
+                wildcards = true;
+                name.push_str(&rest[..close + 2]);
+                rest = &next[close + 1..];
+                continue;
+            },
+            c if KEY_END.contains(c) => break,
+            c => glob.push_str(&regex::escape(&c.to_string())),
+        }
+        name.push(c);
+        rest = next;
+    }
+    if rest.len() == i.len() {
+        return Err(nom::Err::Error(QueryError { input: i, expected: Vec::new() }));
+    }
+
+    // A lone `*` is the plain wildcard, which also selects sequence elements,
+    // so an escaped `*` key is matched through a pattern instead
+    let source = &i[..i.len() - rest.len()];
+    if source == "*" {
+        return Ok((rest, (name, None, None)));
+    }
+    if !wildcards && name != "*" {
+        // Plain keys read as YAML scalars, escaped ones are always strings
+        let literal = if source == name { Yaml::from_str(&name) } else { Yaml::String(name.clone()) };
+        return Ok((rest, (name, None, Some(literal))));
+    }
+    glob.push('$');
+    match Pattern::new(&glob) {
+        Ok(pattern) => Ok((rest, (source.to_owned(), Some(pattern), None))),
+        Err(_) => Err(nom::Err::Failure(QueryError { input: i, expected: vec!["glob pattern"] })),
+    }
+}
+
+/// Key matched by equality: `'...'` or `"..."` for a string and `` `...` ``
+/// for a key written in YAML flow syntax, such as `` `[1, 2]` ``
+#[allow(unused)]
+fn literal_key(i: &str) -> IResult<'_, Key> {
+    if let Ok((rest, s)) = quoted_string(i) {
+        return Ok((rest, (s.clone(), None, Some(Yaml::String(s)))));
+    }
+    if i.starts_with(['"', '\'']) {
+        return Err(nom::Err::Failure(QueryError { input: &i[1..], expected: vec!["closing quote"] }));
+    }
+    let (rest, source) = sequence::delimited(
+        character::char('`'),
+        combinator::cut(bytes::is_not("`")),
+        combinator::cut(expect(&["`` ` ``"], character::char('`')))
+    )(i)?;
+    match yaml_rust::YamlLoader::load_from_str(source) {
+        Ok(mut docs) if docs.len() == 1 => Ok((rest, (source.to_owned(), None, Some(docs.remove(0))))),
+        _ => Err(nom::Err::Failure(QueryError { input: &i[1..], expected: vec!["YAML key"] })),
+    }
+}
+
+/// Regex for the glob class `[content]`, negated by a leading `!`. A class
+/// that reads as a subscript is written with its first character escaped,
+/// as in `[\0]`.
+fn glob_class(content: &str) -> String {
+    let (negated, content) = match content.strip_prefix('!') {
+        Some(content) => (true, content),
+        None => (false, content),
+    };
+    let mut class = String::from(if negated { "[^" } else { "[" });
+    let mut chars = content.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '-' => class.push('-'),
+            '\\' => class.push_str(&regex::escape(&chars.next().unwrap_or('\\').to_string())),
+            c => class.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    class.push(']');
+    class
+}
+
+/// `~/regex/` key, `\/` stands for a slash inside the expression
+#[allow(unused)]
+fn regex_key(i: &str) -> IResult<'_, Key> {
+    let body = &i[2..];
+    let mut re = String::new();
+    let mut chars = body.char_indices();
+    while let Some((pos, c)) = chars.next() {
+        match c {
+            '/' => {
+                let rest = &body[pos + 1..];
+                return match Pattern::new(&re) {
+                    Ok(pattern) => Ok((rest, (i[..i.len() - rest.len()].to_owned(), Some(pattern), None))),
+                    Err(_) => Err(nom::Err::Failure(QueryError { input: i, expected: vec!["regular expression"] })),
+                };
+            },
+            '\\' => match chars.next() {
+                Some((_, '/')) => re.push('/'),
+                Some((_, escaped)) => {
+                    re.push('\\');
+                    re.push(escaped);
+                },
+                None => re.push('\\'),
+            },
+            c => re.push(c),
+        }
+    }
+    Err(nom::Err::Failure(QueryError { input: "", expected: vec!["`/`"] }))
+}
+
+#[allow(unused)]
+fn key_entry(i: &str) -> IResult<'_, PathEntry> {
+    combinator::map(
+        sequence::tuple((
+            combinator::opt(branch::alt((literal_key, key))),
+            combinator::opt(condition_group)
+        )),
+        |(k, c)| {
+            let (key, pattern, literal) = match k {
+                Some((key, pattern, literal)) => (Some(key), pattern, literal),
+                None => (None, None, None),
+            };
+            PathEntry {
+                key,
+                pattern,
+                literal,
+                subscript: None,
+                condition: c,
+                recursive: false,
+            }
+        }
+    )(i)
+}
+
+#[allow(unused)]
+fn integer(i: &str) -> IResult<'_, i64> {
+    combinator::map_res(
+        combinator::recognize(
+            sequence::pair(combinator::opt(character::char('-')), character::digit1)
+        ),
+        |s: &str| s.parse::<i64>()
+    )(i)
+}
+
+/// Whether the content of a bracket is made of subscript tokens only:
+/// numbers with an optional leading `-`, `,`, `:` and spaces
+fn subscript_like(content: &str) -> bool {
+    let mut previous = ',';
+    !content.trim().is_empty() && content.chars().all(|c| {
+        let valid = match c {
+            '-' => ",: \t".contains(previous),
+            c => c.is_ascii_digit() || ",: \t".contains(c),
+        };
+        previous = c;
+        valid
+    })
+}
+
+/// Array subscript: `[i]`, `[i,j,k]` or `[start:end:step]`. Brackets whose
+/// content is made of subscript tokens are always subscripts, so `[1 2]` is
+/// an error, other ones such as `[0-9]` are left to glob classes.
+#[allow(unused)]
+fn subscript(i: &str) -> IResult<'_, Subscript> {
+    sequence::preceded(
+        sequence::pair(
+            character::char('['),
+            combinator::peek(combinator::verify(bytes::take_till(|c| c == ']'), subscript_like))
+        ),
+        combinator::cut(sequence::terminated(
+            expect(&["index", "slice"], branch::alt((
+                combinator::map(
+                    sequence::tuple((
+                        trim(combinator::opt(integer)),
+                        character::char(':'),
+                        trim(combinator::opt(integer)),
+                        combinator::opt(
+                            sequence::preceded(character::char(':'), trim(combinator::opt(integer)))
+                        ),
+                    )),
+                    |(start, _, end, step)| Subscript::Slice { start, end, step: step.flatten() }
+                ),
+                combinator::map(
+                    multi::separated_nonempty_list(character::char(','), trim(integer)),
+                    |mut list| if list.len() == 1 { Subscript::Index(list.remove(0)) } else { Subscript::List(list) }
+                ),
+            ))),
+            expect(&["`,`", "`:`", "`]`"], character::char(']'))
+        ))
+    )(i)
+}
+
+#[allow(unused)]
+fn subscript_entry(i: &str) -> IResult<'_, PathEntry> {
+    combinator::map(
+        sequence::pair(subscript, combinator::opt(condition_group)),
+        |(s, c)| {
+            PathEntry {
+                key: None,
+                pattern: None,
+                literal: None,
+                subscript: Some(s),
+                condition: c,
+                recursive: false,
+            }
+        }
+    )(i)
+}
+
+#[allow(unused)]
+fn path_entry(i: &str) -> IResult<'_, PathEntry> {
+    branch::alt((subscript_entry, key_entry))(i)
+}
+
+/// Path entry after a `.` separator, after `..` for recursive descent, or
+/// a subscript directly following the previous entry
+#[allow(unused)]
+fn segment(i: &str) -> IResult<'_, PathEntry> {
+    branch::alt((
+        combinator::map(
+            sequence::pair(
+                branch::alt((
+                    combinator::value(true, bytes::tag("..")),
+                    combinator::value(false, character::char('.')),
+                )),
+                path_entry
+            ),
+            |(recursive, entry)| PathEntry { recursive, ..entry }
+        ),
+        subscript_entry,
+    ))(i)
+}
+
+#[allow(unused)]
+fn path(i: &str) -> IResult<'_, Vec<PathEntry>> {
+    combinator::map(
+        sequence::tuple((
+            combinator::opt(bytes::tag("..")),
+            path_entry,
+            multi::many0(segment),
+        )),
+        |(recursive, first, mut rest)| {
+            rest.insert(0, PathEntry { recursive: recursive.is_some(), ..first });
+            rest
+        }
+    )(i)
+}
+
+/// Document selector: `#` with an optional index or `*` and an optional condition
+#[allow(unused)]
+fn document(i: &str) -> IResult<'_, PathEntry> {
+    combinator::map(
+        sequence::preceded(
+            character::char('#'),
+            sequence::tuple((
+                combinator::opt(
+                    branch::alt((
+                        character::digit1,
+                        bytes::tag("*"),
+                    ))
+                ),
+                combinator::opt(condition_group)
+            ))
+        ),
+        |(p, c): (Option<&str>, _)| {
+            PathEntry {
+                key: p.map(|p| p.to_owned()),
+                pattern: None,
+                literal: None,
+                subscript: None,
+                condition: c,
+                recursive: false,
+            }
+        }
+    )(i)
+}
+
+/// Characters ending the name of a projection field unless escaped
+const NAME_END: &str = "\\:,{} \t";
+
+#[allow(unused)]
+fn field(i: &str) -> IResult<'_, Field> {
+    trim(
+        branch::alt((
+            combinator::map(
+                sequence::separated_pair(
+                    bytes::escaped_transform(bytes::is_not(NAME_END), '\\', bytes::is_a(NAME_END)),
+                    trim(character::char(':')),
+                    combinator::cut(expect(&["value"], value))
+                ),
+                |(name, value)| Field { name, value }
+            ),
+            combinator::map_opt(value, |value| {
+                let name = match &value {
+                    Statement::Path(path) | Statement::Root(path) | Statement::Parent(_, path) => key_name(path.last()?),
+                    Statement::Function { name, .. } => name.clone(),
+                    _ => return None,
+                };
+                Some(Field { name, value })
+            }),
+        ))
+    )(i)
+}
+
+/// Text of a key as a field name
+fn key_name(key: &Yaml) -> String {
+    match key {
+        Yaml::String(s) | Yaml::Real(s) => s.clone(),
+        Yaml::Integer(i) => i.to_string(),
+        Yaml::Boolean(b) => b.to_string(),
+        key => PathSegment::Key(key.clone()).to_string(),
+    }
+}
+
+/// `{field, ...}`
+#[allow(unused)]
+fn projection(i: &str) -> IResult<'_, Vec<Field>> {
+    sequence::preceded(
+        trim(character::char('{')),
+        combinator::cut(sequence::terminated(
+            multi::separated_list(character::char(','), field),
+            expect(&["`,`", "`}`"], trim(character::char('}')))
+        ))
+    )(i)
+}
+
+#[allow(unused)]
+fn query(i: &str) -> IResult<'_, Query> {
     combinator::map(
         trim(
             sequence::tuple((
-                multi::separated_nonempty_list(
-                    character::char('.'),
+                branch::alt((
                     combinator::map(
                         sequence::tuple((
-                            combinator::opt(
-                                bytes::escaped_transform(
-                                    bytes::is_not("\\. \t=<>!&|^()"),
-                                    '\\',
-                                    bytes::is_a("\\. \t()"),
-                                ),
-                            ),
-                            combinator::opt(
-                                trim(
-                                    sequence::delimited(
-                                        bytes::tag("("),
-                                        condition_list,
-                                        bytes::tag(")"),
-                                    )
-                                )
-                            )
+                            document,
+                            multi::many0(segment)
                         )),
-                        |(p, c)| {
-                            PathEntry {
-                                key: p,
-                                condition: c
-                            }
-                        }
-                    )
-                ),
-                combinator::opt(
-                    sequence::tuple((
-                        compare_sign,
-                        value,
-                    ))
-                )
+                        |(d, p)| (Some(d), p)
+                    ),
+                    combinator::map(path, |p| (None, p)),
+                )),
+                combinator::opt(comparison),
+                combinator::opt(projection)
             ))
         ),
-        |(path, opt)| Query { path: path }
+        |((document, path), filter, projection)| Query {
+            document,
+            path,
+            filter: filter.map(|(sign, right)| Condition { left: Statement::Path(Vec::new()), sign, right }),
+            projection,
+        }
     )(i)
 }
 
+/// Function names that are aggregate stages rather than keys
+const AGGREGATES: &[&str] = &["count", "sum", "min", "max", "avg", "unique", "sort_by", "group_by"];
+
+/// `count()`, `sum()`, `min()`, `max()`, `avg()`, `unique()`,
+/// `sort_by(value)`, `group_by(value)` or `group_by(#)`
+#[allow(unused)]
+fn aggregate(i: &str) -> IResult<'_, Aggregate> {
+    // Other names are keys followed by a condition, whose arguments must not
+    // be parsed as function arguments
+    let (_, name) = bytes::take_while1(|c: char| c.is_alphanumeric() || c == '_')(i)?;
+    if !AGGREGATES.contains(&name) {
+        return Err(nom::Err::Error(QueryError { input: i, expected: Vec::new() }));
+    }
+    let by_document: IResult<'_, _> = sequence::tuple((
+        bytes::tag("group_by("),
+        trim(character::char('#')),
+        character::char(')'),
+    ))(i);
+    if let Ok((rest, _)) = by_document {
+        return Ok((rest, Aggregate::GroupByDocument));
+    }
+    let (rest, (name, mut args)) = match function(i)? {
+        (rest, Statement::Function { name, args }) => (rest, (name, args)),
+        _ => unreachable!(),
+    };
+    let aggregate = match (name.as_str(), args.len()) {
+        ("count", 0) => Aggregate::Count,
+        ("sum", 0) => Aggregate::Sum,
+        ("min", 0) => Aggregate::Min,
+        ("max", 0) => Aggregate::Max,
+        ("avg", 0) => Aggregate::Avg,
+        ("unique", 0) => Aggregate::Unique,
+        ("sort_by", 1) => Aggregate::SortBy(args.remove(0)),
+        ("group_by", 1) => Aggregate::GroupBy(args.remove(0)),
+        ("count", _) | ("sum", _) | ("min", _) | ("max", _) | ("avg", _) | ("unique", _) => {
+            return Err(nom::Err::Failure(QueryError { input: &i[name.len()..], expected: vec!["`()`"] }));
+        },
+        ("sort_by", _) | ("group_by", _) => {
+            return Err(nom::Err::Failure(QueryError { input: &i[name.len()..], expected: vec!["single argument"] }));
+        },
+        _ => unreachable!(),
+    };
+    Ok((rest, aggregate))
+}
+
+/// Stage after a `|`, a relative query, `select(condition)` or an aggregate
 #[allow(unused)]
-pub fn parse_query(i: &str) -> Result<Query, std::io::Error> {
-    let parse_result = combinator::all_consuming(query)(i);
-    match parse_result {
-        Ok((i, q)) => Result::Ok(q),
-        Err(e) => Result::Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidInput,
-            format!("{}", e)
+fn stage(i: &str) -> IResult<'_, Stage> {
+    trim(sequence::preceded(
+        combinator::peek(character::none_of("|")),
+        branch::alt((
+            combinator::map(sequence::preceded(bytes::tag("select"), condition_group), Stage::Select),
+            combinator::map(aggregate, Stage::Aggregate),
+            // `..key` is a recursive query, while `.key` starts from the match
+            combinator::map(sequence::preceded(combinator::peek(bytes::tag("..")), query), |q| Stage::Query(Box::new(q))),
+            combinator::map(sequence::preceded(character::char('.'), query), |q| Stage::Query(Box::new(q))),
+            combinator::map(query, |q| Stage::Query(Box::new(q))),
         ))
+    ))(i)
+}
+
+#[allow(unused)]
+fn pipeline(i: &str) -> IResult<'_, Pipeline> {
+    combinator::map(
+        sequence::pair(
+            query,
+            multi::many0(sequence::preceded(
+                character::char('|'),
+                combinator::cut(expect(&["query", "`select`"], stage))
+            ))
+        ),
+        |(first, rest)| {
+            let mut stages = vec![Stage::Query(Box::new(first))];
+            stages.extend(rest);
+            Pipeline { stages }
+        }
+    )(i)
+}
+
+/// Parses `query | stage | ...`, a plain query is a single stage pipeline
+pub fn parse_pipeline(i: &str) -> Result<Pipeline, ParseError> {
+    match pipeline(i) {
+        Ok(("", p)) => Ok(p),
+        Ok((rest, _)) => Err(parse_error(i, rest, PIPELINE_END)),
+        Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => Err(parse_error(i, e.input, &e.expected)),
+        Err(nom::Err::Incomplete(_)) => Err(parse_error(i, "", &[])),
+    }
+}
+
+pub fn parse_query(i: &str) -> Result<Query, ParseError> {
+    match query(i) {
+        Ok(("", q)) => Ok(q),
+        Ok((rest, _)) => Err(parse_error(i, rest, QUERY_END)),
+        Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => Err(parse_error(i, e.input, &e.expected)),
+        Err(nom::Err::Incomplete(_)) => Err(parse_error(i, "", &[])),
+    }
+}
+
+fn parse_error(query: &str, rest: &str, expected: &[&str]) -> ParseError {
+    ParseError {
+        offset: query.len() - rest.len(),
+        expected: expected.iter().map(|token| token.to_string()).collect(),
+        fragment: rest.split_whitespace().next().unwrap_or("").to_owned(),
     }
 }
 
@@ -236,19 +885,33 @@ mod tests {
     
     #[test]
     fn test_unescaped_path() {
-        assert_eq!(unescaped_path("first"), Ok(("", vec!["first".to_owned()])));
-        assert_eq!(unescaped_path("fir\\\\st"), Ok(("", vec!["fir\\st".to_owned()])));
-        assert_eq!(unescaped_path("first.second"), Ok(("", vec!["first".to_owned(), "second".to_owned()])));
-        assert_eq!(unescaped_path("first.sec\\.ond"), Ok(("", vec!["first".to_owned(), "sec.ond".to_owned()])));
-        assert_eq!(unescaped_path(""), Err(nom::Err::Error(("", nom::error::ErrorKind::SeparatedList))));
+        let string = |s: &str| Yaml::String(s.to_owned());
+        assert_eq!(unescaped_path("first"), Ok(("", vec![string("first")])));
+        assert_eq!(unescaped_path("fir\\\\st"), Ok(("", vec![string("fir\\st")])));
+        assert_eq!(unescaped_path("first.second"), Ok(("", vec![string("first"), string("second")])));
+        assert_eq!(unescaped_path("first.sec\\.ond"), Ok(("", vec![string("first"), string("sec.ond")])));
+
+        // Keys are typed as in query paths
+        assert_eq!(unescaped_path("codes.200"), Ok(("", vec![string("codes"), Yaml::Integer(200)])));
+        assert_eq!(unescaped_path("codes.'200'"), Ok(("", vec![string("codes"), string("200")])));
+        assert_eq!(unescaped_path("codes.\\200"), Ok(("", vec![string("codes"), string("200")])));
+        assert_eq!(unescaped_path("a.`[1]`"), Ok(("", vec![string("a"), Yaml::Array(vec![Yaml::Integer(1)])])));
+        assert_eq!(
+            unescaped_path("labels.'app.kubernetes.io/name' =="),
+            Ok(("==", vec![string("labels"), string("app.kubernetes.io/name")]))
+        );
+        assert!(matches!(unescaped_path("labels.'app"), Err(nom::Err::Failure(_))));
+        assert_eq!(unescaped_path(""), Err(nom::Err::Error(QueryError { input: "", expected: vec![] })));
     }
 
     #[test]
     fn test_quoted_string() {
-        assert_eq!(quoted_string("\"hello\""), Ok(("", "hello")));
-        assert_eq!(quoted_string("\"he\\\"llo\""), Ok(("", "he\\\"llo")));
-        assert_eq!(quoted_string("'hello'"), Ok(("", "hello")));
-        assert_eq!(quoted_string("'he\\'llo'"), Ok(("", "he\\'llo")));
+        assert_eq!(quoted_string("\"hello\""), Ok(("", "hello".to_owned())));
+        assert_eq!(quoted_string("\"he\\\"llo\""), Ok(("", "he\"llo".to_owned())));
+        assert_eq!(quoted_string("'hello'"), Ok(("", "hello".to_owned())));
+        assert_eq!(quoted_string("'he\\'llo'"), Ok(("", "he'llo".to_owned())));
+        assert_eq!(quoted_string("'a\\\\b\"c'"), Ok(("", "a\\b\"c".to_owned())));
+        assert_eq!(quoted_string("''"), Ok(("", "".to_owned())));
     }
 
     #[test]
@@ -257,8 +920,11 @@ mod tests {
         assert_eq!(value("false"), Ok(("", Statement::Boolean(false))));
         assert_eq!(value("null"), Ok(("", Statement::None)));
         assert_eq!(value("\"hello\""), Ok(("", Statement::String("hello".to_owned()))));
-        assert_eq!(value("first_underscored"), Ok(("", Statement::Path(vec!["first_underscored".to_owned()]))));
-        assert_eq!(value("first.second"), Ok(("", Statement::Path(vec!["first".to_owned(), "second".to_owned()]))));
+        assert_eq!(value("99999999999999999999"), Ok(("", Statement::Double(1e20))));
+        assert_eq!(value("-99999999999999999999"), Ok(("", Statement::Double(-1e20))));
+        assert!(parse_query("services.*(scale > 99999999999999999999)").is_ok());
+        assert_eq!(value("first_underscored"), Ok(("", Statement::Path(vec![Yaml::String("first_underscored".to_owned())]))));
+        assert_eq!(value("first.second"), Ok(("", Statement::Path(vec![Yaml::String("first".to_owned()), Yaml::String("second".to_owned())]))));
         assert_eq!(value("10"), Ok(("", Statement::Integer(10))));
         assert_eq!(value("-10"), Ok(("", Statement::Integer(-10))));
         assert_eq!(value("1.1"), Ok(("", Statement::Double(1.1))));
@@ -273,7 +939,7 @@ mod tests {
             right: Statement::None,
         })));
         assert_eq!(condition("first.second==null"), Ok(("", Condition {
-            left: Statement::Path(vec!["first".to_owned(), "second".to_owned()]),
+            left: Statement::Path(vec![Yaml::String("first".to_owned()), Yaml::String("second".to_owned())]),
             sign: CompareSign::Eq,
             right: Statement::None,
         })));
@@ -315,7 +981,7 @@ mod tests {
         )));
         assert_eq!(condition_list("first&&(true==false)"), Ok(("",
             vec![
-                ConditionListItem::Statement(Statement::Path(vec!["first".to_owned()])),
+                ConditionListItem::Statement(Statement::Path(vec![Yaml::String("first".to_owned())])),
                 ConditionListItem::Relation(Relation::And),
                 ConditionListItem::Group(vec![
                     ConditionListItem::Condition(Condition {
@@ -326,11 +992,27 @@ mod tests {
                 ])
             ]
         )));
+        assert_eq!(condition_list("!first && !!(true != false)"), Ok(("",
+            vec![
+                ConditionListItem::Not,
+                ConditionListItem::Statement(Statement::Path(vec![Yaml::String("first".to_owned())])),
+                ConditionListItem::Relation(Relation::And),
+                ConditionListItem::Not,
+                ConditionListItem::Not,
+                ConditionListItem::Group(vec![
+                    ConditionListItem::Condition(Condition {
+                        left: Statement::Boolean(true),
+                        sign: CompareSign::Ne,
+                        right: Statement::Boolean(false)
+                    })
+                ])
+            ]
+        )));
         assert_eq!(condition_list("first.value && (false || true != false)"), Ok(("", 
             vec![
                 ConditionListItem::Statement(Statement::Path(vec![
-                    "first".to_owned(),
-                    "value".to_owned(),
+                    Yaml::String("first".to_owned()),
+                    Yaml::String("value".to_owned()),
                 ])),
                 ConditionListItem::Relation(Relation::And),
                 ConditionListItem::Group(
@@ -352,41 +1034,495 @@ mod tests {
     fn test_query() {
         assert_eq!(query("first.second"), Ok(("",
             Query { 
+                document: None,
+                filter: None,
+                projection: None,
                 path: vec![
                     PathEntry {
                         key: Some("first".to_owned()),
+                        pattern: None,
+                        literal: Some(Yaml::String("first".to_owned())),
+                        subscript: None,
                         condition: None,
+                        recursive: false,
                     },
                     PathEntry {
                         key: Some("second".to_owned()),
+                        pattern: None,
+                        literal: Some(Yaml::String("second".to_owned())),
+                        subscript: None,
                         condition: None,
+                        recursive: false,
                     },
                 ] 
             }
         )));
         assert_eq!(query("first.*(aaa.bbb == 'some_value').third"), Ok(("",
             Query { 
+                document: None,
+                filter: None,
+                projection: None,
                 path: vec![
                     PathEntry {
                         key: Some("first".to_owned()),
+                        pattern: None,
+                        literal: Some(Yaml::String("first".to_owned())),
+                        subscript: None,
                         condition: None,
+                        recursive: false,
                     },
                     PathEntry {
                         key: Some("*".to_owned()),
+                        pattern: None,
+                        literal: None,
+                        subscript: None,
                         condition: Some(vec![
                             ConditionListItem::Condition(Condition {
-                                left: Statement::Path(vec!["aaa".to_owned(), "bbb".to_owned()]),
+                                left: Statement::Path(vec![Yaml::String("aaa".to_owned()), Yaml::String("bbb".to_owned())]),
                                 sign: CompareSign::Eq,
                                 right: Statement::String("some_value".to_owned()),
                             })
                         ]),
+                        recursive: false,
                     },
                     PathEntry {
                         key: Some("third".to_owned()),
+                        pattern: None,
+                        literal: Some(Yaml::String("third".to_owned())),
+                        subscript: None,
                         condition: None,
+                        recursive: false,
                     },
                 ] 
             }
         )));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_parse_query_errors() {
+        assert_eq!(parse_query("services.*(scale > 0"), Err(ParseError {
+            offset: 20,
+            expected: OPERAND_END.iter().map(|t| t.to_string()).collect(),
+            fragment: "".to_owned(),
+        }));
+        assert_eq!(parse_query("services.*(scale => 0).image"), Err(ParseError {
+            offset: 17,
+            expected: OPERAND_END.iter().map(|t| t.to_string()).collect(),
+            fragment: "=>".to_owned(),
+        }));
+        assert_eq!(parse_query("services.*(scale > ).image"), Err(ParseError {
+            offset: 19,
+            expected: vec!["value".to_owned()],
+            fragment: ").image".to_owned(),
+        }));
+        assert_eq!(parse_query("services.*(scale > 0 && )"), Err(ParseError {
+            offset: 24,
+            expected: vec!["condition".to_owned()],
+            fragment: ")".to_owned(),
+        }));
+        assert_eq!(parse_query("services.db image"), Err(ParseError {
+            offset: 12,
+            expected: QUERY_END.iter().map(|t| t.to_string()).collect(),
+            fragment: "image".to_owned(),
+        }));
+    }
+
+    #[test]
+    fn test_query_document() {
+        assert_eq!(query("#1.first"), Ok(("",
+            Query {
+                document: Some(PathEntry {
+                    key: Some("1".to_owned()),
+                    pattern: None,
+                    literal: None,
+                    subscript: None,
+                    condition: None,
+                    recursive: false,
+                }),
+                path: vec![
+                    PathEntry {
+                        key: Some("first".to_owned()),
+                        pattern: None,
+                        literal: Some(Yaml::String("first".to_owned())),
+                        subscript: None,
+                        condition: None,
+                        recursive: false,
+                    },
+                ],
+                filter: None,
+                projection: None,
+            }
+        )));
+        assert_eq!(query("#(kind == 'Service')"), Ok(("",
+            Query {
+                document: Some(PathEntry {
+                    key: None,
+                    pattern: None,
+                    literal: None,
+                    subscript: None,
+                    condition: Some(vec![
+                        ConditionListItem::Condition(Condition {
+                            left: Statement::Path(vec![Yaml::String("kind".to_owned())]),
+                            sign: CompareSign::Eq,
+                            right: Statement::String("Service".to_owned()),
+                        })
+                    ]),
+                    recursive: false,
+                }),
+                path: vec![],
+                filter: None,
+                projection: None,
+            }
+        )));
+        assert_eq!(query("\\#1"), Ok(("",
+            Query {
+                document: None,
+                filter: None,
+                projection: None,
+                path: vec![
+                    PathEntry {
+                        key: Some("#1".to_owned()),
+                        pattern: None,
+                        literal: Some(Yaml::String("#1".to_owned())),
+                        subscript: None,
+                        condition: None,
+                        recursive: false,
+                    },
+                ]
+            }
+        )));
+    }
+
+    #[test]
+    fn test_query_recursive() {
+        let entries = |q: &str| -> Vec<(Option<String>, bool)> {
+            parse_query(q).unwrap().path.into_iter().map(|e| (e.key, e.recursive)).collect()
+        };
+        assert_eq!(entries("..image"), vec![(Some("image".to_owned()), true)]);
+        assert_eq!(entries("services..env.name"), vec![
+            (Some("services".to_owned()), false),
+            (Some("env".to_owned()), true),
+            (Some("name".to_owned()), false),
+        ]);
+        assert_eq!(entries("#0..image"), vec![(Some("image".to_owned()), true)]);
+        assert_eq!(entries("a..*"), vec![(Some("a".to_owned()), false), (Some("*".to_owned()), true)]);
+        let q = parse_query("services..env(name == 'DEBUG')").unwrap();
+        assert!(q.path[1].recursive && q.path[1].condition.is_some());
+    }
+
+    #[test]
+    fn test_query_subscript() {
+        let subscripts = |q: &str| -> Vec<Option<Subscript>> {
+            parse_query(q).unwrap().path.into_iter().map(|e| e.subscript).collect()
+        };
+        assert_eq!(subscripts("list[-1]"), vec![None, Some(Subscript::Index(-1))]);
+        assert_eq!(subscripts("list.[ 0, 2,5 ]"), vec![None, Some(Subscript::List(vec![0, 2, 5]))]);
+        assert_eq!(subscripts("list[1:-1].name"), vec![
+            None,
+            Some(Subscript::Slice { start: Some(1), end: Some(-1), step: None }),
+            None,
+        ]);
+        assert_eq!(subscripts("[::-2]"), vec![
+            Some(Subscript::Slice { start: None, end: None, step: Some(-2) }),
+        ]);
+        assert_eq!(subscripts("a\\[0]"), vec![None]);
+        let q = parse_query("..list[0](name == 'db')").unwrap();
+        assert!(q.path[0].recursive && q.path[1].condition.is_some());
+
+        // Brackets that are not subscripts are glob classes
+        let q = parse_query("list[x]").unwrap();
+        assert_eq!(q.path.len(), 1);
+        assert_eq!(q.path[0].pattern.as_ref().map(|p| p.as_str()), Some("^list[x]$"));
+        let q = parse_query("db[0-9]").unwrap();
+        assert_eq!(q.path[0].pattern.as_ref().map(|p| p.as_str()), Some("^db[0-9]$"));
+        let q = parse_query("db[\\0]").unwrap();
+        assert_eq!(q.path[0].pattern.as_ref().map(|p| p.as_str()), Some("^db[0]$"));
+        assert_eq!(subscripts("list[ -1 , 2 ]"), vec![None, Some(Subscript::List(vec![-1, 2]))]);
+        assert_eq!(parse_query("list[1 2]"), Err(ParseError {
+            offset: 7,
+            expected: vec!["`,`".to_owned(), "`:`".to_owned(), "`]`".to_owned()],
+            fragment: "2]".to_owned(),
+        }));
+        assert_eq!(parse_query("list[1"), Err(ParseError {
+            offset: 6,
+            expected: vec!["`,`".to_owned(), "`:`".to_owned(), "`]`".to_owned()],
+            fragment: "".to_owned(),
+        }));
+        assert_eq!(parse_query("list[x"), Err(ParseError {
+            offset: 6,
+            expected: vec!["`]`".to_owned()],
+            fragment: "".to_owned(),
+        }));
+    }
+
+    #[test]
+    fn test_query_patterns() {
+        let keys = |q: &str| -> Vec<(Option<String>, Option<String>)> {
+            parse_query(q).unwrap().path.into_iter()
+                .map(|e| (e.key, e.pattern.map(|p| p.as_str().to_owned())))
+                .collect()
+        };
+        assert_eq!(keys("svc-*.image"), vec![
+            (Some("svc-*".to_owned()), Some("^svc\\-.*$".to_owned())),
+            (Some("image".to_owned()), None),
+        ]);
+        assert_eq!(keys("a?[!b-c]"), vec![(Some("a?[!b-c]".to_owned()), Some("^a.[^b-c]$".to_owned()))]);
+        assert_eq!(keys("*"), vec![(Some("*".to_owned()), None)]);
+        assert_eq!(keys("a\\*\\.b"), vec![(Some("a*.b".to_owned()), None)]);
+        assert_eq!(keys("~/^db\\d+$/.~/a\\/b/"), vec![
+            (Some("~/^db\\d+$/".to_owned()), Some("^db\\d+$".to_owned())),
+            (Some("~/a\\/b/".to_owned()), Some("a/b".to_owned())),
+        ]);
+        assert_eq!(parse_query("~/(/"), Err(ParseError {
+            offset: 0,
+            expected: vec!["regular expression".to_owned()],
+            fragment: "~/(/".to_owned(),
+        }));
+        assert_eq!(parse_query("a.~/b"), Err(ParseError {
+            offset: 5,
+            expected: vec!["`/`".to_owned()],
+            fragment: "".to_owned(),
+        }));
+    }
+
+    #[test]
+    fn test_string_operators() {
+        fn sign(c: &str) -> Result<(&str, CompareSign, Statement), nom::Err<QueryError<'_>>> {
+            condition(c).map(|(rest, c)| (rest, c.sign, c.right))
+        }
+        assert_eq!(sign("image ^= 'postgres'"), Ok(("", CompareSign::StartsWith, Statement::String("postgres".to_owned()))));
+        assert_eq!(sign("image $= 'x'").unwrap().1, CompareSign::EndsWith);
+        assert_eq!(sign("image *= 'x'").unwrap().1, CompareSign::Contains);
+        assert_eq!(sign("image !~ 'x'").unwrap().2, Statement::Pattern(Pattern::new("x").unwrap()));
+        assert_eq!(sign("image =~ tag").unwrap().2, Statement::Path(vec![Yaml::String("tag".to_owned())]));
+        assert_eq!(sign("tier in ['web', 2, [] ]"), Ok(("", CompareSign::In, Statement::List(vec![
+            Statement::String("web".to_owned()),
+            Statement::Integer(2),
+            Statement::List(vec![]),
+        ]))));
+        assert!(sign("tier index").is_err());
+        assert_eq!(parse_query("a(b =~ '(')"), Err(ParseError {
+            offset: 7,
+            expected: vec!["regular expression".to_owned()],
+            fragment: "'(')".to_owned(),
+        }));
+        assert_eq!(parse_query("a(b in [1, 2)"), Err(ParseError {
+            offset: 12,
+            expected: vec!["`,`".to_owned(), "`]`".to_owned()],
+            fragment: ")".to_owned(),
+        }));
+    }
+
+    #[test]
+    fn test_function() {
+        assert_eq!(value("length(image) "), Ok(("", Statement::Function {
+            name: "length".to_owned(),
+            args: vec![Statement::Path(vec![Yaml::String("image".to_owned())])],
+        })));
+        assert_eq!(value("default( a.b , to_number('1'))"), Ok(("", Statement::Function {
+            name: "default".to_owned(),
+            args: vec![
+                Statement::Path(vec![Yaml::String("a".to_owned()), Yaml::String("b".to_owned())]),
+                Statement::Function { name: "to_number".to_owned(), args: vec![Statement::String("1".to_owned())] },
+            ],
+        })));
+        assert_eq!(value("now()"), Ok(("", Statement::Function { name: "now".to_owned(), args: vec![] })));
+        assert_eq!(parse_query("a(length(b > 1)"), Err(ParseError {
+            offset: 11,
+            expected: vec!["`,`".to_owned(), "`)`".to_owned()],
+            fragment: ">".to_owned(),
+        }));
+    }
+
+    #[test]
+    fn test_reference() {
+        let path = |keys: &[&str]| keys.iter().map(|k| Yaml::String((*k).to_owned())).collect::<Vec<_>>();
+        assert_eq!(value("$.defaults.scale"), Ok(("", Statement::Root(path(&["defaults", "scale"])))));
+        assert_eq!(value("$ "), Ok(("", Statement::Root(vec![]))));
+        assert_eq!(value("@.scale"), Ok(("", Statement::Path(path(&["scale"])))));
+        assert_eq!(value("@)"), Ok((")", Statement::Path(vec![]))));
+        assert_eq!(value("^.image"), Ok(("", Statement::Parent(1, path(&["image"])))));
+        assert_eq!(value("^^^"), Ok(("", Statement::Parent(3, vec![]))));
+        assert_eq!(value("..replicas"), Ok(("", Statement::Parent(1, path(&["replicas"])))));
+        assert_eq!(value("$schema"), Ok(("", Statement::Path(path(&["$schema"])))));
+        assert_eq!(value("@type.name"), Ok(("", Statement::Path(path(&["@type", "name"])))));
+        assert_eq!(condition("scale > $.defaults.scale"), Ok(("", Condition {
+            left: Statement::Path(path(&["scale"])),
+            sign: CompareSign::Gt,
+            right: Statement::Root(path(&["defaults", "scale"])),
+        })));
+    }
+
+    #[test]
+    fn test_query_filter() {
+        let q = parse_query("services.*.image == 'nginx'").unwrap();
+        assert_eq!(q.path.len(), 3);
+        assert_eq!(q.filter, Some(Condition {
+            left: Statement::Path(vec![]),
+            sign: CompareSign::Eq,
+            right: Statement::String("nginx".to_owned()),
+        }));
+        let q = parse_query("a.b=~'^x'").unwrap();
+        assert_eq!(q.filter.map(|f| f.right), Some(Statement::Pattern(Pattern::new("^x").unwrap())));
+        assert_eq!(parse_query("a.b").unwrap().filter, None);
+        assert_eq!(parse_query("a.b == "), Err(ParseError {
+            offset: 7,
+            expected: vec!["value".to_owned()],
+            fragment: "".to_owned(),
+        }));
+    }
+
+    #[test]
+    fn test_projection() {
+        let path = |keys: &[&str]| Statement::Path(keys.iter().map(|k| Yaml::String((*k).to_owned())).collect());
+        let q = parse_query("services.*{image, replicas: scale, port: ports.0, length(image)}").unwrap();
+        assert_eq!(q.path.len(), 2);
+        assert_eq!(q.path[1].key, Some("*".to_owned()));
+        assert_eq!(q.projection, Some(vec![
+            Field { name: "image".to_owned(), value: path(&["image"]) },
+            Field { name: "replicas".to_owned(), value: path(&["scale"]) },
+            Field { name: "port".to_owned(), value: Statement::Path(vec![Yaml::String("ports".to_owned()), Yaml::Integer(0)]) },
+            Field {
+                name: "length".to_owned(),
+                value: Statement::Function { name: "length".to_owned(), args: vec![path(&["image"])] },
+            },
+        ]));
+        let q = parse_query("a(b > 1).c == 1 { root: $.name, a\\:b: 'x', deep.key }").unwrap();
+        assert!(q.filter.is_some());
+        assert_eq!(q.projection, Some(vec![
+            Field { name: "root".to_owned(), value: Statement::Root(vec![Yaml::String("name".to_owned())]) },
+            Field { name: "a:b".to_owned(), value: Statement::String("x".to_owned()) },
+            Field { name: "key".to_owned(), value: path(&["deep", "key"]) },
+        ]));
+        assert_eq!(parse_query("a{}").unwrap().projection, Some(vec![]));
+        assert_eq!(parse_query("a\\{b").unwrap().path[0].key, Some("a{b".to_owned()));
+        assert_eq!(parse_query("a{b, 1}"), Err(ParseError {
+            offset: 3,
+            expected: vec!["`,`".to_owned(), "`}`".to_owned()],
+            fragment: ",".to_owned(),
+        }));
+        assert_eq!(parse_query("a{b: }"), Err(ParseError {
+            offset: 5,
+            expected: vec!["value".to_owned()],
+            fragment: "}".to_owned(),
+        }));
+    }
+
+    #[test]
+    fn test_pipeline() {
+        let p = parse_pipeline("services.* | select(scale > 0) | .image").unwrap();
+        assert_eq!(p.stages.len(), 3);
+        assert_eq!(p.stages[0], Stage::Query(Box::new(parse_query("services.*").unwrap())));
+        assert_eq!(p.stages[1], Stage::Select(vec![
+            ConditionListItem::Condition(Condition {
+                left: Statement::Path(vec![Yaml::String("scale".to_owned())]),
+                sign: CompareSign::Gt,
+                right: Statement::Integer(0),
+            })
+        ]));
+        assert_eq!(p.stages[2], Stage::Query(Box::new(parse_query("image").unwrap())));
+
+        let p = parse_pipeline("a|..b|.c == 1{d}|select(!e)").unwrap();
+        assert_eq!(p.stages[1], Stage::Query(Box::new(parse_query("..b").unwrap())));
+        assert_eq!(p.stages[2], Stage::Query(Box::new(parse_query("c == 1{d}").unwrap())));
+        assert!(matches!(p.stages[3], Stage::Select(_)));
+        assert_eq!(parse_pipeline("a.b").unwrap().stages, vec![Stage::Query(Box::new(parse_query("a.b").unwrap()))]);
+
+        assert_eq!(parse_pipeline("a | select(b"), Err(ParseError {
+            offset: 12,
+            expected: OPERAND_END.iter().map(|t| t.to_string()).collect(),
+            fragment: "".to_owned(),
+        }));
+        assert_eq!(parse_pipeline("a | "), Err(ParseError {
+            offset: 4,
+            expected: vec!["query".to_owned(), "`select`".to_owned()],
+            fragment: "".to_owned(),
+        }));
+        assert_eq!(parse_pipeline("a || b"), Err(ParseError {
+            offset: 3,
+            expected: vec!["query".to_owned(), "`select`".to_owned()],
+            fragment: "|".to_owned(),
+        }));
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let stages = |p: &str| parse_pipeline(p).unwrap().stages;
+        assert_eq!(stages("a | count()")[1], Stage::Aggregate(Aggregate::Count));
+        assert_eq!(stages("a | group_by( # )")[1], Stage::Aggregate(Aggregate::GroupByDocument));
+        assert_eq!(stages("a|sum( )|avg()|min()|max()|unique()")[1..], [
+            Stage::Aggregate(Aggregate::Sum),
+            Stage::Aggregate(Aggregate::Avg),
+            Stage::Aggregate(Aggregate::Min),
+            Stage::Aggregate(Aggregate::Max),
+            Stage::Aggregate(Aggregate::Unique),
+        ]);
+        assert_eq!(
+            stages("services.* | sort_by(scale) | group_by(length(image))")[1..],
+            [
+                Stage::Aggregate(Aggregate::SortBy(Statement::Path(vec![Yaml::String("scale".to_owned())]))),
+                Stage::Aggregate(Aggregate::GroupBy(Statement::Function {
+                    name: "length".to_owned(),
+                    args: vec![Statement::Path(vec![Yaml::String("image".to_owned())])],
+                })),
+            ]
+        );
+        // Other calls are keys with a condition
+        assert!(matches!(&stages("a | counts(b)")[1], Stage::Query(_)));
+        assert!(matches!(&stages("services | db(scale > 0)")[1], Stage::Query(_)));
+        assert!(matches!(&stages("services | db(scale > 0 && count(replicas) == 1)")[1], Stage::Query(_)));
+        assert_eq!(parse_pipeline("a | sum(b)"), Err(ParseError {
+            offset: 7,
+            expected: vec!["`()`".to_owned()],
+            fragment: "(b)".to_owned(),
+        }));
+        assert_eq!(parse_pipeline("a | sort_by()"), Err(ParseError {
+            offset: 11,
+            expected: vec!["single argument".to_owned()],
+            fragment: "()".to_owned(),
+        }));
+    }
+
+    #[test]
+    fn test_typed_keys() {
+        let literals = |q: &str| -> Vec<Option<Yaml>> {
+            parse_query(q).unwrap().path.into_iter().map(|entry| entry.literal).collect()
+        };
+        let string = |s: &str| Some(Yaml::String(s.to_owned()));
+        assert_eq!(literals("codes.200.'200'.\"404\""), vec![string("codes"), Some(Yaml::Integer(200)), string("200"), string("404")]);
+        assert_eq!(literals("a.true.null.~.1.5"), vec![string("a"), Some(Yaml::Boolean(true)), Some(Yaml::Null), Some(Yaml::Null), Some(Yaml::Integer(1)), Some(Yaml::Integer(5))]);
+        assert_eq!(literals("\\200.*.2*.''"), vec![string("200"), None, None, string("")]);
+        assert_eq!(literals("`[1, 2]`.`{a: 1}`(b == 1)"), vec![
+            Some(Yaml::Array(vec![Yaml::Integer(1), Yaml::Integer(2)])),
+            yaml_rust::YamlLoader::load_from_str("{a: 1}").unwrap().pop(),
+        ]);
+        assert_eq!(parse_query("a.'b.c'").unwrap().path[1].key, Some("b.c".to_owned()));
+        assert_eq!(parse_query("a.`[1`"), Err(ParseError {
+            offset: 3,
+            expected: vec!["YAML key".to_owned()],
+            fragment: "[1`".to_owned(),
+        }));
+        assert_eq!(parse_query("a.`b"), Err(ParseError {
+            offset: 4,
+            expected: vec!["`` ` ``".to_owned()],
+            fragment: "".to_owned(),
+        }));
+    }
+
+    #[test]
+    fn test_quoted_keys() {
+        let keys = |q: &str| -> Vec<Option<String>> {
+            parse_query(q).unwrap().path.into_iter().map(|entry| entry.key).collect()
+        };
+        let key = |s: &str| Some(s.to_owned());
+        assert_eq!(keys("metadata.labels.\"app.kubernetes.io/name\""), vec![key("metadata"), key("labels"), key("app.kubernetes.io/name")]);
+        assert_eq!(keys("'my key (old)'.x"), vec![key("my key (old)"), key("x")]);
+        assert_eq!(keys("'it\\'s'.\"say \\\"hi\\\"\".'a\\\\b'"), vec![key("it's"), key("say \"hi\""), key("a\\b")]);
+        assert!(parse_query("a.'b c'(d == 1)").unwrap().path[1].condition.is_some());
+        assert_eq!(parse_query("a.'b"), Err(ParseError {
+            offset: 3,
+            expected: vec!["closing quote".to_owned()],
+            fragment: "b".to_owned(),
+        }));
+    }
+}